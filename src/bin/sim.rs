@@ -0,0 +1,202 @@
+// Simulated STM32 bootloader on a pseudo-terminal, for testing the CLI and scripts
+// without hardware: stm32-firmware-loader -p <printed path> flash firmware.bin
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread::sleep;
+use std::time::Duration;
+
+use clap::{App, Arg};
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::termios::{self, ControlFlags, SetArg};
+use parse_int::parse;
+use stm32_firmware_loader::device;
+use stm32_firmware_loader::mock::{MockConfig, MockDevice};
+use stm32_firmware_loader::Command;
+
+fn main() {
+    env_logger::builder()
+        .parse_filters("info")
+        .parse_default_env()
+        .try_init()
+        .expect("Could not init Logging System");
+
+    let matches = App::new("STM32 Bootloader Simulator")
+        .version("1.0")
+        .about("Emulates an STM32 bootloader on a pseudo-terminal")
+        .arg(
+            Arg::with_name("device")
+                .short('d')
+                .long("device")
+                .value_name("PID")
+                .help("Product ID of the emulated device, selects flash layout and RAM")
+                .takes_value(true)
+                .default_value("0x413"),
+        )
+        .arg(
+            Arg::with_name("pid")
+                .long("pid")
+                .value_name("PID")
+                .help("Product ID reported by Get ID, defaults to the one of the device")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bootloader-version")
+                .long("bootloader-version")
+                .value_name("VERSION")
+                .help("Bootloader version reported by Get, e.g. 0x31 for v3.1")
+                .takes_value(true)
+                .default_value("0x31"),
+        )
+        .arg(
+            Arg::with_name("legacy-erase")
+                .long("legacy-erase")
+                .help("Support Erase Memory instead of Extended Erase Memory"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .long("checksum")
+                .help("Support Get Checksum (bootloader v3.3+)"),
+        )
+        .arg(
+            Arg::with_name("erase-time")
+                .long("erase-time")
+                .value_name("MS")
+                .help("Time erasing a page or sector takes in milliseconds")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .get_matches();
+
+    let pid: u16 = parse(matches.value_of("device").unwrap()).expect("invalid product ID");
+    let device = device::lookup(pid).expect("unknown device");
+    let mut config = MockConfig::for_device(device);
+    if let Some(pid) = matches.value_of("pid") {
+        config.pid = parse(pid).expect("invalid product ID");
+    }
+    config.version =
+        parse(matches.value_of("bootloader-version").unwrap()).expect("invalid bootloader version");
+    if matches.is_present("legacy-erase") {
+        config
+            .commands
+            .retain(|&opcode| opcode != Command::ExtendedErase.opcode());
+        config.commands.push(Command::Erase.opcode());
+    }
+    if matches.is_present("checksum") {
+        config.commands.push(Command::GetChecksum.opcode());
+    }
+    let erase_time: u64 = matches
+        .value_of("erase-time")
+        .unwrap()
+        .parse()
+        .expect("invalid erase time");
+    config.erase_time = Duration::from_millis(erase_time);
+
+    let (mut master, slave, path) = open_pty().expect("Failed to open pseudo-terminal");
+    println!("Emulating {} on {}", device, path);
+    let check_parity = keeps_parity(&slave).expect("Failed to set up pseudo-terminal");
+    if !check_parity {
+        log::info!("the kernel does not keep the parity setting, it is not checked");
+    }
+
+    let mut device = MockDevice::new(config);
+    if let Err(e) = run(&mut master, &slave, &mut device, check_parity) {
+        println!("Error: {}", e);
+    }
+}
+
+// Opens a pseudo-terminal in raw mode. The slave side is kept open so the master
+// can be read while no host is connected.
+fn open_pty() -> io::Result<(PtyMaster, File, String)> {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let path = ptsname_r(&master)?;
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(OFlag::O_NOCTTY.bits())
+        .open(&path)?;
+    let mut settings = termios::tcgetattr(&slave)?;
+    termios::cfmakeraw(&mut settings);
+    termios::tcsetattr(&slave, SetArg::TCSANOW, &settings)?;
+    Ok((master, slave, path))
+}
+
+// Whether parity can be set on the pseudo-terminal, some kernels reject or clear it
+fn keeps_parity(slave: &File) -> io::Result<bool> {
+    let raw = termios::tcgetattr(slave)?;
+    let mut settings = raw.clone();
+    settings.control_flags |= ControlFlags::PARENB;
+    if termios::tcsetattr(slave, SetArg::TCSANOW, &settings).is_err() {
+        return Ok(false);
+    }
+    let keeps = termios::tcgetattr(slave)?
+        .control_flags
+        .contains(ControlFlags::PARENB);
+    termios::tcsetattr(slave, SetArg::TCSANOW, &raw)?;
+    Ok(keeps)
+}
+
+// With check_parity, data received while the terminal is not set to 8 data bits with
+// even parity is dropped, a real bootloader sees framing or parity errors
+fn run(
+    master: &mut PtyMaster,
+    slave: &File,
+    device: &mut MockDevice,
+    check_parity: bool,
+) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    let mut even_parity = true;
+    let mut jumped_to = None;
+    loop {
+        let len = master.read(&mut buf)?;
+        if len == 0 {
+            // nothing to read, do not spin
+            sleep(Duration::from_millis(10));
+            continue;
+        }
+
+        if check_parity {
+            let flags = termios::tcgetattr(slave)?.control_flags;
+            let parity = flags.contains(ControlFlags::CS8)
+                && flags.contains(ControlFlags::PARENB)
+                && !flags.contains(ControlFlags::PARODD);
+            if parity != even_parity {
+                if parity {
+                    log::info!("terminal set to even parity");
+                } else {
+                    log::warn!("terminal not set to 8 data bits and even parity");
+                }
+                even_parity = parity;
+            }
+            if !parity {
+                log::debug!("dropped: {:02X?}", &buf[..len]);
+                continue;
+            }
+        }
+
+        log::debug!("host: {:02X?}", &buf[..len]);
+        device.write_all(&buf[..len])?;
+        let mut answer = Vec::new();
+        loop {
+            match device.read(&mut buf) {
+                Ok(len) => answer.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if !answer.is_empty() {
+            log::debug!("device: {:02X?}", answer);
+            master.write_all(&answer)?;
+        }
+
+        if device.jumped_to() != jumped_to {
+            jumped_to = device.jumped_to();
+            if let Some(address) = jumped_to {
+                log::info!("application started at {:#010X}", address);
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::io::prelude::*;
+use std::str::FromStr;
+
+use crate::device::{self, ExpectedChip, Sector};
+use crate::{
+    BootloaderError, Command, MemoryImage, SkipPolicy, SpecialEraseType, SpecialResponse,
+    WriteOptions,
+};
+
+// What to erase, see Bootloader::erase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseTarget<'a> {
+    Pages(&'a [u16]),
+    Mass,
+    Bank1,
+    Bank2,
+}
+
+// What to erase before flashing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EraseStrategy {
+    // Erase the whole flash memory
+    #[default]
+    Mass,
+    // Erase only the pages or sectors the data is written to, based on the device flash layout
+    CoveredSectorsOnly,
+    // Erase and write only the pages or sectors whose contents differ from the data,
+    // see Bootloader::diff_sectors
+    ChangedSectorsOnly,
+    // Do not erase, the memory has to be erased already
+    None,
+}
+
+impl FromStr for EraseStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mass" => Ok(EraseStrategy::Mass),
+            "sectors" => Ok(EraseStrategy::CoveredSectorsOnly),
+            "changed" => Ok(EraseStrategy::ChangedSectorsOnly),
+            "none" => Ok(EraseStrategy::None),
+            _ => Err(format!("Unknown erase strategy: {}", s)),
+        }
+    }
+}
+
+// Pages or sectors covered by an image, split by whether the device already holds the data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SectorDiff {
+    pub changed: Vec<Sector>,
+    pub unchanged: Vec<Sector>,
+}
+
+impl SectorDiff {
+    // The part of the image in the changed sectors
+    pub fn changed_image(&self, image: &MemoryImage) -> Result<MemoryImage, BootloaderError> {
+        let mut changed = MemoryImage::new();
+        for sector in &self.changed {
+            changed.merge(&image.cropped(sector.address..sector.address + sector.size))?;
+        }
+        changed.entry = image.entry;
+        Ok(changed)
+    }
+}
+
+// A session with the bootloader.
+// Version and supported commands are queried once when the session is created,
+// commands the bootloader does not support fail with BootloaderError::Unsupported
+// instead of being sent to the device.
+pub struct Bootloader<T> {
+    port: T,
+    version: u8,
+    commands: Vec<u8>,
+    // Product ID, queried by the first Get ID
+    pid: Option<u16>,
+}
+
+impl<T: Read + Write> Bootloader<T> {
+    // Synchronises with the bootloader and queries its capabilities
+    pub fn new(mut port: T) -> Result<Self, BootloaderError> {
+        crate::hello(&mut port)?;
+        Self::attach(port)
+    }
+
+    // Queries the capabilities of a bootloader the port is already synchronised with,
+    // e.g. a port opened by helper::connect_port
+    pub fn attach(mut port: T) -> Result<Self, BootloaderError> {
+        let (version, commands) = crate::get(&mut port)?;
+        log::debug!(
+            "bootloader version {}.{}, commands: {:02X?}",
+            version >> 4,
+            version & 0xF,
+            commands
+        );
+        Ok(Bootloader {
+            port,
+            version,
+            commands,
+            pid: None,
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn commands(&self) -> &[u8] {
+        &self.commands
+    }
+
+    pub fn supports(&self, command: Command) -> bool {
+        command == Command::Hello || self.commands.contains(&command.opcode())
+    }
+
+    pub fn port(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    fn require(&self, command: Command) -> Result<(), BootloaderError> {
+        if self.supports(command) {
+            Ok(())
+        } else {
+            Err(BootloaderError::Unsupported(command))
+        }
+    }
+
+    // The command list can change after a system reset (e.g. once readout protection is active)
+    fn refresh(&mut self) -> Result<(), BootloaderError> {
+        let (version, commands) = crate::get(&mut self.port)?;
+        self.version = version;
+        self.commands = commands;
+        Ok(())
+    }
+
+    pub fn get_version(&mut self) -> Result<u8, BootloaderError> {
+        self.require(Command::GetVersion)?;
+        crate::get_version(&mut self.port)
+    }
+
+    pub fn get_id(&mut self) -> Result<u16, BootloaderError> {
+        self.require(Command::GetId)?;
+        let pid = crate::get_id(&mut self.port)?;
+        self.pid = Some(pid);
+        Ok(pid)
+    }
+
+    // Looks up the device by its product ID
+    pub fn device(&mut self) -> Result<&'static device::Device, BootloaderError> {
+        let pid = self.get_id()?;
+        device::lookup(pid).ok_or(BootloaderError::UnknownDevice(pid))
+    }
+
+    // Checks the device before anything is erased: it has to be the expected chip and
+    // the image has to fit into its flash memory. The size of the flash memory is the one
+    // of the largest variant of the device. Returns the device if it is known.
+    pub fn check_target(
+        &mut self,
+        expected: Option<ExpectedChip>,
+        image: &MemoryImage,
+    ) -> Result<Option<&'static device::Device>, BootloaderError> {
+        let pid = self.get_id()?;
+        if let Some(expected) = expected {
+            if !expected.matches(pid) {
+                return Err(BootloaderError::WrongChip { expected, pid });
+            }
+        }
+        let device = device::lookup(pid);
+        if let Some(device) = device {
+            let flash = &device.flash;
+            for segment in image.segments() {
+                if !flash.contains(segment.address) {
+                    return Err(BootloaderError::OutsideFlash {
+                        address: segment.address,
+                        flash_base: flash.base,
+                        flash_end: flash.end(),
+                    });
+                }
+                if segment.end() > flash.end() as u64 {
+                    return Err(BootloaderError::ImageTooLarge {
+                        end: segment.end(),
+                        flash_end: flash.end(),
+                    });
+                }
+            }
+        }
+        Ok(device)
+    }
+
+    pub fn read_memory(
+        &mut self,
+        address: u32,
+        dst_data: &mut [u8],
+    ) -> Result<(), BootloaderError> {
+        self.require(Command::ReadMemory)?;
+        crate::read_memory(&mut self.port, address, dst_data)
+    }
+
+    pub fn read_memory_vec(
+        &mut self,
+        address: u32,
+        num_bytes: usize,
+    ) -> Result<Vec<u8>, BootloaderError> {
+        self.require(Command::ReadMemory)?;
+        crate::read_memory_vec(&mut self.port, address, num_bytes)
+    }
+
+    pub fn write_memory(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        options: WriteOptions,
+    ) -> Result<(), BootloaderError> {
+        self.require(Command::WriteMemory)?;
+        // writes to the RAM of the bootloader are refused for known devices
+        if self.supports(Command::GetId) {
+            let pid = match self.pid {
+                Some(pid) => pid,
+                None => self.get_id()?,
+            };
+            if let Some(device) = device::lookup(pid) {
+                device.check_write(address, data.len())?;
+            }
+        }
+        crate::write_memory(&mut self.port, address, data, options)
+    }
+
+    pub fn go(&mut self, address: u32) -> Result<(), BootloaderError> {
+        self.require(Command::Go)?;
+        crate::go(&mut self.port, address)
+    }
+
+    pub fn get_checksum(&mut self, address: u32, len: u32) -> Result<u32, BootloaderError> {
+        self.require(Command::GetChecksum)?;
+        crate::get_checksum(&mut self.port, address, len)
+    }
+
+    // Verifies using Get Checksum if supported, otherwise the memory is read back.
+    // Only data is compared, not the padding added by write_memory.
+    pub fn verify_memory(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        options: WriteOptions,
+    ) -> Result<(), BootloaderError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.supports(Command::GetChecksum) {
+            log::debug!("verifying using Get Checksum");
+            crate::verify_memory_checksum(&mut self.port, address, data, options)
+        } else {
+            self.require(Command::ReadMemory)?;
+            crate::verify_memory_readback(&mut self.port, address, data, options)
+        }
+    }
+
+    // Erases using Extended Erase if supported, otherwise the legacy Erase command is used.
+    // Bank erase and page numbers above 255 are only available with Extended Erase.
+    pub fn erase(&mut self, target: EraseTarget) -> Result<(), BootloaderError> {
+        if self.supports(Command::ExtendedErase) {
+            return match target {
+                EraseTarget::Pages(pages) => crate::extended_erase(&mut self.port, pages),
+                EraseTarget::Mass => {
+                    crate::extended_erase_special(&mut self.port, SpecialEraseType::MassErase)
+                }
+                EraseTarget::Bank1 => {
+                    crate::extended_erase_special(&mut self.port, SpecialEraseType::Bank1Erase)
+                }
+                EraseTarget::Bank2 => {
+                    crate::extended_erase_special(&mut self.port, SpecialEraseType::Bank2Erase)
+                }
+            };
+        }
+        self.require(Command::Erase)?;
+        match target {
+            EraseTarget::Pages(pages) => {
+                let pages = pages
+                    .iter()
+                    .map(|&page| u8::try_from(page))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|_| BootloaderError::Unsupported(Command::ExtendedErase))?;
+                crate::erase_memory(&mut self.port, &pages)
+            }
+            EraseTarget::Mass => crate::erase_memory_global(&mut self.port),
+            EraseTarget::Bank1 | EraseTarget::Bank2 => {
+                Err(BootloaderError::Unsupported(Command::ExtendedErase))
+            }
+        }
+    }
+
+    // Erases the memory needed to write len bytes at address.
+    // CoveredSectorsOnly looks up the flash layout by the product ID of the device.
+    pub fn erase_for_write(
+        &mut self,
+        strategy: EraseStrategy,
+        address: u32,
+        len: usize,
+    ) -> Result<(), BootloaderError> {
+        self.erase_regions(strategy, &[(address, len)])
+    }
+
+    // Compares every page or sector covered by the image with the data on the device,
+    // using Get Checksum if supported
+    pub fn diff_sectors(
+        &mut self,
+        image: &MemoryImage,
+        options: WriteOptions,
+    ) -> Result<SectorDiff, BootloaderError> {
+        let device = self.device()?;
+        // skipped blocks would hide differences
+        let options = WriteOptions {
+            skip: SkipPolicy::Never,
+            ..options
+        };
+        let sectors = device.flash.sectors_covering_image(image);
+
+        let mut diff = SectorDiff::default();
+        for sector in sectors {
+            let expected = image.cropped(sector.address..sector.address + sector.size);
+            let mut changed = false;
+            for segment in expected.segments() {
+                match self.verify_memory(segment.address, &segment.data, options) {
+                    Ok(()) => {}
+                    Err(BootloaderError::Mismatch { .. }) => {
+                        changed = true;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            log::trace!("sector {} changed: {}", sector.index, changed);
+            if changed {
+                diff.changed.push(sector);
+            } else {
+                diff.unchanged.push(sector);
+            }
+        }
+        Ok(diff)
+    }
+
+    // Erases the memory needed to write all segments of the image
+    pub fn erase_for_image(
+        &mut self,
+        strategy: EraseStrategy,
+        image: &MemoryImage,
+    ) -> Result<(), BootloaderError> {
+        let regions = image
+            .segments()
+            .iter()
+            .map(|segment| (segment.address, segment.data.len()))
+            .collect::<Vec<_>>();
+        self.erase_regions(strategy, &regions)
+    }
+
+    fn erase_regions(
+        &mut self,
+        strategy: EraseStrategy,
+        regions: &[(u32, usize)],
+    ) -> Result<(), BootloaderError> {
+        match strategy {
+            EraseStrategy::Mass => self.erase(EraseTarget::Mass),
+            // the changed part of the image is passed in
+            EraseStrategy::CoveredSectorsOnly | EraseStrategy::ChangedSectorsOnly => {
+                let device = self.device()?;
+                let mut pages = regions
+                    .iter()
+                    .flat_map(|&(address, len)| device.flash.sectors_covering(address, len))
+                    .map(|sector| sector.index)
+                    .collect::<Vec<u16>>();
+                pages.sort_unstable();
+                pages.dedup();
+                log::debug!(
+                    "Erasing {} pages of {}: {:?}",
+                    pages.len(),
+                    device.name,
+                    pages
+                );
+                if pages.is_empty() {
+                    return Ok(());
+                }
+                self.erase(EraseTarget::Pages(&pages))
+            }
+            EraseStrategy::None => Ok(()),
+        }
+    }
+
+    pub fn write_protect(&mut self, sectors: &[u8]) -> Result<(), BootloaderError> {
+        self.require(Command::WriteProtect)?;
+        crate::write_protect(&mut self.port, sectors)?;
+        self.refresh()
+    }
+
+    pub fn write_unprotect(&mut self) -> Result<(), BootloaderError> {
+        self.require(Command::WriteUnprotect)?;
+        crate::write_unprotect(&mut self.port)?;
+        self.refresh()
+    }
+
+    pub fn readout_protect(&mut self) -> Result<(), BootloaderError> {
+        self.require(Command::ReadoutProtect)?;
+        crate::readout_protect(&mut self.port)?;
+        self.refresh()
+    }
+
+    pub fn readout_unprotect(&mut self) -> Result<(), BootloaderError> {
+        self.require(Command::ReadoutUnprotect)?;
+        crate::readout_unprotect(&mut self.port)?;
+        self.refresh()
+    }
+
+    pub fn special_command(
+        &mut self,
+        opcode: u16,
+        data: &[u8],
+    ) -> Result<SpecialResponse, BootloaderError> {
+        self.require(Command::Special)?;
+        crate::special_command(&mut self.port, opcode, data)
+    }
+
+    pub fn extended_special_command(
+        &mut self,
+        opcode: u16,
+        data: &[u8],
+        data2: &[u8],
+    ) -> Result<SpecialResponse, BootloaderError> {
+        self.require(Command::ExtendedSpecial)?;
+        crate::extended_special_command(&mut self.port, opcode, data, data2)
+    }
+}
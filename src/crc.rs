@@ -0,0 +1,43 @@
+// Host side implementations of the CRCs used by the bootloader and the firmware containers
+
+const STM32_CRC_POLYNOMIAL: u32 = 0x04C1_1DB7;
+const STM32_CRC_INIT: u32 = 0xFFFF_FFFF;
+
+// CRC as computed by the STM32 CRC peripheral in its default configuration
+// (CRC-32 polynomial, initial value 0xFFFFFFFF, no reflection, no final xor).
+// Memory is fed as 32 bit little endian words, the same way the bootloader
+// computes the Get Checksum result. The length of data must be a multiple of 4,
+// trailing bytes are ignored.
+pub fn stm32_crc32(data: &[u8]) -> u32 {
+    let mut crc = STM32_CRC_INIT;
+    for word in data.chunks_exact(4) {
+        crc ^= u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        for _ in 0..32 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ STM32_CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+const DFU_CRC_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+// CRC stored in the DFU file suffix: reflected CRC-32 with initial value 0xFFFFFFFF
+// and no final xor, i.e. the complement of the common CRC-32.
+pub fn dfu_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ DFU_CRC_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,505 @@
+// Decodes captured bootloader traffic into transactions, see trace for the capture formats.
+// The host and device bytes are taken in capture order: a device byte captured before
+// the host finished its part of a command, or a host byte captured while the device
+// should answer, is a protocol violation. Each command is replayed through
+// protocol::Exchange, so the frames and checksums are checked by the same code that
+// sends them.
+use std::fmt;
+use std::time::Duration;
+
+use crate::protocol::{self, Exchange, Request, Response, ACK, HELLO_BYTE, NACK};
+use crate::trace::{Event, TraceEntry};
+use crate::{Command, SpecialEraseType, SpecialResponse};
+
+// Part of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Ack,
+    Nack,
+    Address(u32),
+    // Number of bytes or pages
+    Length(usize),
+    Data(Vec<u8>),
+    Pages(Vec<u16>),
+    GlobalErase,
+    MassErase,
+    Bank1Erase,
+    Bank2Erase,
+    Opcode(u16),
+    Checksum { value: u8, valid: bool },
+    Version(u8),
+    Commands(Vec<u8>),
+    ProductId(u16),
+    Crc(u32),
+    Status(Vec<u8>),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Ack => f.write_str("ACK"),
+            Item::Nack => f.write_str("NACK"),
+            Item::Address(address) => write!(f, "address {:#010X}", address),
+            Item::Length(len) => write!(f, "length {}", len),
+            Item::Data(data) if data.len() > 16 => {
+                write!(f, "data {:02X?}.. ({} bytes)", &data[..16], data.len())
+            }
+            Item::Data(data) => write!(f, "data {:02X?}", data),
+            Item::Pages(pages) => write!(f, "pages {:?}", pages),
+            Item::GlobalErase => f.write_str("global erase"),
+            Item::MassErase => f.write_str("mass erase"),
+            Item::Bank1Erase => f.write_str("bank 1 erase"),
+            Item::Bank2Erase => f.write_str("bank 2 erase"),
+            Item::Opcode(opcode) => write!(f, "opcode {:#06X}", opcode),
+            Item::Checksum { value, valid: true } => write!(f, "checksum {:#04X} ok", value),
+            Item::Checksum {
+                value,
+                valid: false,
+            } => write!(f, "checksum {:#04X} wrong", value),
+            Item::Version(version) => write!(f, "version {}.{}", version >> 4, version & 0xF),
+            Item::Commands(commands) => write!(f, "commands {:02X?}", commands),
+            Item::ProductId(pid) => write!(f, "PID {:#05X}", pid),
+            Item::Crc(crc) => write!(f, "CRC {:#010X}", crc),
+            Item::Status(status) => write!(f, "status {:02X?}", status),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    // Time of the first byte
+    pub time: Duration,
+    // None if the host sent something which is not a command
+    pub command: Option<Command>,
+    pub items: Vec<Item>,
+    pub violations: Vec<String>,
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6} ", self.time.as_secs_f64())?;
+        match self.command {
+            Some(command) => write!(f, "{}", command)?,
+            None => f.write_str("?")?,
+        }
+        for (i, item) in self.items.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{}", separator, item)?;
+        }
+        for violation in &self.violations {
+            write!(f, "\n  ! {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Host,
+    Device,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Byte {
+    time: Duration,
+    direction: Direction,
+    value: u8,
+}
+
+// Decodes all transactions of a capture
+pub fn decode(entries: &[TraceEntry]) -> Vec<Transaction> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        let (direction, data) = match &entry.event {
+            Event::Sent(data) => (Direction::Host, data),
+            Event::Received(data) => (Direction::Device, data),
+            Event::Timeout => continue,
+        };
+        bytes.extend(data.iter().map(|&value| Byte {
+            time: entry.time,
+            direction,
+            value,
+        }));
+    }
+
+    let mut decoder = Decoder {
+        bytes,
+        position: 0,
+        transactions: Vec::new(),
+    };
+    while decoder.position < decoder.bytes.len() {
+        decoder.transaction();
+    }
+    decoder.transactions
+}
+
+struct Decoder {
+    bytes: Vec<Byte>,
+    position: usize,
+    transactions: Vec<Transaction>,
+}
+
+impl Decoder {
+    fn current(&mut self) -> &mut Transaction {
+        self.transactions.last_mut().unwrap()
+    }
+
+    fn violation(&mut self, text: String) {
+        self.current().violations.push(text);
+    }
+
+    fn item(&mut self, item: Item) {
+        self.current().items.push(item);
+    }
+
+    // Takes len bytes sent by one side. Returns None and records the violation
+    // if the capture ends or the other side sends something first.
+    fn take(&mut self, direction: Direction, len: usize) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let Some(byte) = self.bytes.get(self.position).copied() else {
+                self.violation("capture ends in the middle of the command".to_string());
+                return None;
+            };
+            if byte.direction != direction {
+                let text = match direction {
+                    Direction::Host => {
+                        format!("device sent {:#04X} before the host finished", byte.value)
+                    }
+                    Direction::Device => "no answer from the device".to_string(),
+                };
+                self.violation(text);
+                return None;
+            }
+            data.push(byte.value);
+            self.position += 1;
+        }
+        Some(data)
+    }
+
+    fn host(&mut self, len: usize) -> Option<Vec<u8>> {
+        self.take(Direction::Host, len)
+    }
+
+    fn device(&mut self, len: usize) -> Option<Vec<u8>> {
+        self.take(Direction::Device, len)
+    }
+
+    fn transaction(&mut self) {
+        let first = self.bytes[self.position];
+        self.transactions.push(Transaction {
+            time: first.time,
+            command: None,
+            items: Vec::new(),
+            violations: Vec::new(),
+        });
+        if first.direction == Direction::Device {
+            self.position += 1;
+            self.violation(format!(
+                "unexpected byte {:#04X} from the device",
+                first.value
+            ));
+            return;
+        }
+        if first.value == HELLO_BYTE {
+            self.current().command = Some(Command::Hello);
+            self.run(Request::Hello);
+            return;
+        }
+        let Some(command) = Command::from_opcode(first.value) else {
+            if self.host(2).is_some() {
+                self.violation(format!("unknown command {:#04X}", first.value));
+                self.ack();
+            }
+            return;
+        };
+        self.current().command = Some(command);
+        match self.request(command) {
+            Ok(request) => {
+                self.run(request);
+            }
+            Err(violation) => {
+                // the command and the reserved page count
+                if self.host(2).is_some() && self.ack().is_some() && self.host(2).is_some() {
+                    self.violation(violation);
+                }
+            }
+        }
+    }
+
+    // Reads the request the host sent ahead in the capture
+    fn request(&self, command: Command) -> Result<Request, String> {
+        let host = self.bytes[self.position..]
+            .iter()
+            .filter(|byte| byte.direction == Direction::Host)
+            .map(|byte| byte.value)
+            // the command itself
+            .skip(2);
+        let mut ahead = Lookahead(host);
+        let request = match command {
+            Command::Hello => Request::Hello,
+            Command::Get => Request::Get,
+            Command::GetVersion => Request::GetVersion,
+            Command::GetId => Request::GetId,
+            Command::ReadMemory => Request::ReadMemory {
+                address: ahead.word(),
+                len: ahead.byte() as usize + 1,
+            },
+            Command::Go => Request::Go {
+                address: ahead.word(),
+            },
+            Command::WriteMemory => Request::WriteMemory {
+                address: ahead.word(),
+                data: ahead.block(),
+            },
+            Command::Erase => match ahead.byte() {
+                0xFF => Request::GlobalErase,
+                len => Request::Erase {
+                    sectors: ahead.bytes(len as usize + 1),
+                },
+            },
+            Command::ExtendedErase => match ahead.half_word() {
+                0xFFFF => Request::SpecialErase(SpecialEraseType::MassErase),
+                0xFFFE => Request::SpecialErase(SpecialEraseType::Bank1Erase),
+                0xFFFD => Request::SpecialErase(SpecialEraseType::Bank2Erase),
+                count if count >= 0xFFF0 => {
+                    return Err(format!("reserved page count {:#06X}", count))
+                }
+                count => Request::ExtendedErase {
+                    pages: (0..=count).map(|_| ahead.half_word()).collect(),
+                },
+            },
+            Command::WriteProtect => Request::WriteProtect {
+                sectors: ahead.block(),
+            },
+            Command::WriteUnprotect => Request::WriteUnprotect,
+            Command::ReadoutProtect => Request::ReadoutProtect,
+            Command::ReadoutUnprotect => Request::ReadoutUnprotect,
+            Command::GetChecksum => Request::GetChecksum {
+                address: ahead.word(),
+                len: ahead.word(),
+            },
+            Command::Special => {
+                let opcode = ahead.half_word();
+                Request::Special {
+                    opcode,
+                    data: ahead.length_prefixed(),
+                }
+            }
+            Command::ExtendedSpecial => {
+                let opcode = ahead.half_word();
+                Request::ExtendedSpecial {
+                    opcode,
+                    data: ahead.length_prefixed(),
+                    data2: ahead.length_prefixed(),
+                }
+            }
+        };
+        Ok(request)
+    }
+
+    // Replays the request: the frames the exchange sends are compared with the
+    // captured ones and the captured answer of the device is fed into it
+    fn run(&mut self, request: Request) -> Option<()> {
+        let command = request.command();
+        let mut exchange = Exchange::unchecked(request.clone());
+        let mut frames = 0;
+        // last piece of the answer and whether an ACK followed it
+        let mut answer = Vec::new();
+        let mut acked = false;
+        loop {
+            match exchange.poll() {
+                protocol::Event::Send(frame) => {
+                    let data = self.host(frame.len())?;
+                    let (&value, &expected) = (data.last()?, frame.last()?);
+                    if frames == 0 {
+                        if value != expected {
+                            self.violation(format!("wrong complement {:#04X}", value));
+                        }
+                    } else {
+                        for item in frame_items(&request, frames) {
+                            self.item(item);
+                        }
+                        let valid = value == expected;
+                        self.item(Item::Checksum { value, valid });
+                        if !valid {
+                            self.violation("wrong checksum".to_string());
+                        }
+                    }
+                    frames += 1;
+                }
+                protocol::Event::Receive(len) => {
+                    let ack = exchange.waits_for_ack();
+                    let data = self.device(len)?;
+                    if ack && data[0] != ACK {
+                        return self.ack_error(data[0]);
+                    }
+                    let result = exchange.receive(&data);
+                    if ack {
+                        self.item(Item::Ack);
+                    } else {
+                        answer = data;
+                    }
+                    acked = ack;
+                    if result.is_err() {
+                        self.invalid_answer(command, &answer, acked);
+                        return None;
+                    }
+                }
+                protocol::Event::Done(response) => {
+                    self.answer(response_items(command, response), acked);
+                    return Some(());
+                }
+            }
+        }
+    }
+
+    fn ack(&mut self) -> Option<()> {
+        match self.device(1)?[0] {
+            ACK => {
+                self.item(Item::Ack);
+                Some(())
+            }
+            byte => self.ack_error(byte),
+        }
+    }
+
+    fn ack_error(&mut self, byte: u8) -> Option<()> {
+        if byte == NACK {
+            self.item(Item::Nack);
+        } else {
+            self.violation(format!("expected ACK or NACK, got {:#04X}", byte));
+        }
+        None
+    }
+
+    // Adds the items of the answer, before the ACK if the device sent one after it
+    fn answer(&mut self, items: Vec<Item>, acked: bool) {
+        let transaction = self.current();
+        let at = transaction.items.len() - acked as usize;
+        transaction.items.splice(at..at, items);
+    }
+
+    // Only the answers of Get ID and Get Checksum can fail to decode
+    fn invalid_answer(&mut self, command: Command, answer: &[u8], acked: bool) {
+        if command == Command::GetChecksum {
+            self.answer(
+                vec![
+                    Item::Crc(u32::from_be_bytes([
+                        answer[0], answer[1], answer[2], answer[3],
+                    ])),
+                    Item::Checksum {
+                        value: answer[4],
+                        valid: false,
+                    },
+                ],
+                acked,
+            );
+            self.violation("wrong checksum of the CRC".to_string());
+        } else {
+            self.answer(vec![Item::Data(answer.to_vec())], acked);
+            self.violation(format!("product ID of {} bytes", answer.len()));
+        }
+    }
+}
+
+// Items of the frame with the given index the host sends for the request,
+// the command itself being frame 0
+fn frame_items(request: &Request, frame: usize) -> Vec<Item> {
+    match (request, frame) {
+        (Request::ReadMemory { address, .. }, 1)
+        | (Request::Go { address }, 1)
+        | (Request::WriteMemory { address, .. }, 1)
+        | (Request::GetChecksum { address, .. }, 1) => vec![Item::Address(*address)],
+        (Request::ReadMemory { len, .. }, 2) => vec![Item::Length(*len)],
+        (Request::WriteMemory { data, .. }, 2) => {
+            vec![Item::Length(data.len()), Item::Data(data.clone())]
+        }
+        (Request::GetChecksum { len, .. }, 2) => vec![Item::Length(*len as usize)],
+        (Request::Erase { sectors }, _) | (Request::WriteProtect { sectors }, _) => {
+            vec![Item::Pages(sectors.iter().map(|&s| s as u16).collect())]
+        }
+        (Request::GlobalErase, _) => vec![Item::GlobalErase],
+        (Request::ExtendedErase { pages }, _) => vec![Item::Pages(pages.clone())],
+        (Request::SpecialErase(erase), _) => vec![match erase {
+            SpecialEraseType::MassErase => Item::MassErase,
+            SpecialEraseType::Bank1Erase => Item::Bank1Erase,
+            SpecialEraseType::Bank2Erase => Item::Bank2Erase,
+        }],
+        (Request::Special { opcode, data }, _)
+        | (Request::ExtendedSpecial { opcode, data, .. }, 1) => {
+            vec![Item::Opcode(*opcode), Item::Data(data.clone())]
+        }
+        (Request::ExtendedSpecial { data2, .. }, _) => vec![Item::Data(data2.clone())],
+        _ => Vec::new(),
+    }
+}
+
+fn response_items(command: Command, response: Response) -> Vec<Item> {
+    match response {
+        Response::Ack => Vec::new(),
+        Response::Get { version, commands } => {
+            vec![Item::Version(version), Item::Commands(commands)]
+        }
+        Response::Version(version) => vec![Item::Version(version)],
+        Response::Id(pid) => vec![Item::ProductId(pid)],
+        Response::Data(data) => vec![Item::Data(data)],
+        Response::Checksum(crc) => vec![
+            Item::Crc(crc),
+            Item::Checksum {
+                value: protocol::checksum(&crc.to_be_bytes(), 0),
+                valid: true,
+            },
+        ],
+        // Extended Special only returns the status
+        Response::Special(SpecialResponse { status, .. })
+            if command == Command::ExtendedSpecial =>
+        {
+            vec![Item::Status(status)]
+        }
+        Response::Special(SpecialResponse { data, status }) => {
+            vec![Item::Data(data), Item::Status(status)]
+        }
+    }
+}
+
+// Host bytes ahead of the decoder, without the device bytes in between. Bytes
+// missing at the end of the capture read as 0, the replay stops there anyway.
+struct Lookahead<I>(I);
+
+impl<I: Iterator<Item = u8>> Lookahead<I> {
+    fn byte(&mut self) -> u8 {
+        self.0.next().unwrap_or(0)
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.byte()).collect()
+    }
+
+    fn half_word(&mut self) -> u16 {
+        u16::from_be_bytes([self.byte(), self.byte()])
+    }
+
+    // Address or length followed by its checksum
+    fn word(&mut self) -> u32 {
+        let word = u32::from_be_bytes([self.byte(), self.byte(), self.byte(), self.byte()]);
+        self.byte();
+        word
+    }
+
+    // Number of bytes minus one, the bytes and the checksum
+    fn block(&mut self) -> Vec<u8> {
+        let len = self.byte() as usize + 1;
+        let data = self.bytes(len);
+        self.byte();
+        data
+    }
+
+    // 2 byte length, the bytes and the checksum, as sent by the special commands
+    fn length_prefixed(&mut self) -> Vec<u8> {
+        let len = self.half_word() as usize;
+        let data = self.bytes(len);
+        self.byte();
+        data
+    }
+}
@@ -0,0 +1,908 @@
+// Device table for the product IDs returned by Get ID.
+// Memory layouts and bootloader RAM usage as listed in AN2606 and the reference manuals:
+// https://www.st.com/resource/en/application_note/an2606-stm32-microcontroller-system-memory-boot-mode-stmicroelectronics.pdf
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::{BootloaderError, MemoryImage};
+
+const KB: u32 = 1024;
+const FLASH_BASE: u32 = 0x0800_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    C0,
+    F0,
+    F1,
+    F2,
+    F3,
+    F4,
+    F7,
+    G0,
+    G4,
+    H7,
+    L0,
+    L1,
+    L4,
+    L5,
+    U5,
+    WB,
+    WL,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "STM32{:?}", self)
+    }
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    // Accepts the name with or without the STM32 prefix, e.g. "STM32F4" or "f4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let name = upper.strip_prefix("STM32").unwrap_or(&upper);
+        let family = match name {
+            "C0" => Family::C0,
+            "F0" => Family::F0,
+            "F1" => Family::F1,
+            "F2" => Family::F2,
+            "F3" => Family::F3,
+            "F4" => Family::F4,
+            "F7" => Family::F7,
+            "G0" => Family::G0,
+            "G4" => Family::G4,
+            "H7" => Family::H7,
+            "L0" => Family::L0,
+            "L1" => Family::L1,
+            "L4" => Family::L4,
+            "L5" => Family::L5,
+            "U5" => Family::U5,
+            "WB" => Family::WB,
+            "WL" => Family::WL,
+            _ => return Err(format!("Unknown family: {}", s)),
+        };
+        Ok(family)
+    }
+}
+
+// Chip a flash operation is restricted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedChip {
+    Pid(u16),
+    Family(Family),
+}
+
+impl ExpectedChip {
+    pub fn matches(&self, pid: u16) -> bool {
+        match self {
+            ExpectedChip::Pid(expected) => *expected == pid,
+            ExpectedChip::Family(family) => lookup(pid).is_some_and(|d| d.family == *family),
+        }
+    }
+}
+
+impl FromStr for ExpectedChip {
+    type Err = String;
+
+    // A product ID like "0x413" or a family name like "STM32F4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(pid) = parse_int::parse::<u16>(s) {
+            return Ok(ExpectedChip::Pid(pid));
+        }
+        s.parse()
+            .map(ExpectedChip::Family)
+            .map_err(|_| format!("Expected a product ID or a family name: {}", s))
+    }
+}
+
+impl fmt::Display for ExpectedChip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectedChip::Pid(pid) => write!(f, "{:#05X}", pid),
+            ExpectedChip::Family(family) => write!(f, "{}", family),
+        }
+    }
+}
+
+// Consecutive pages or sectors of the same size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorRun {
+    pub count: u16,
+    pub size: u32,
+}
+
+// A single page or sector, index is the number used by the erase commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sector {
+    pub index: u16,
+    pub address: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashLayout {
+    pub base: u32,
+    pub sectors: &'static [SectorRun],
+}
+
+impl FlashLayout {
+    // Size of the largest flash variant with this product ID
+    pub fn size(&self) -> u32 {
+        self.sectors
+            .iter()
+            .map(|run| run.count as u32 * run.size)
+            .sum()
+    }
+
+    pub fn end(&self) -> u32 {
+        self.base + self.size()
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        (self.base..self.end()).contains(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Sector> + '_ {
+        self.sectors
+            .iter()
+            .flat_map(|run| std::iter::repeat_n(run.size, run.count as usize))
+            .scan((0, self.base), |(index, address), size| {
+                let sector = Sector {
+                    index: *index,
+                    address: *address,
+                    size,
+                };
+                *index += 1;
+                *address += size;
+                Some(sector)
+            })
+    }
+
+    // Page or sector containing the address
+    pub fn sector_at(&self, address: u32) -> Option<Sector> {
+        self.iter()
+            .find(|sector| (sector.address..sector.address + sector.size).contains(&address))
+    }
+
+    // Pages or sectors touched by writing len bytes at address
+    pub fn sectors_covering(&self, address: u32, len: usize) -> Vec<Sector> {
+        let end = address as u64 + len as u64;
+        self.iter()
+            .filter(|sector| {
+                (sector.address as u64) < end && address < sector.address + sector.size
+            })
+            .collect()
+    }
+
+    // Pages or sectors touched by writing the image
+    pub fn sectors_covering_image(&self, image: &MemoryImage) -> Vec<Sector> {
+        let mut sectors = image
+            .segments()
+            .iter()
+            .flat_map(|segment| self.sectors_covering(segment.address, segment.data.len()))
+            .collect::<Vec<Sector>>();
+        sectors.dedup_by_key(|sector| sector.index);
+        sectors
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub pid: u16,
+    pub family: Family,
+    pub name: &'static str,
+    pub flash: FlashLayout,
+    pub ram: &'static [Range<u32>],
+    pub option_bytes: u32,
+    // RAM used by the bootloader itself, must not be written
+    pub bootloader_ram: Range<u32>,
+    // Programming unit in bytes if it differs from the family, see write_granularity
+    pub write_unit: Option<usize>,
+}
+
+impl Device {
+    // Value erased flash memory reads as, the L0 and L1 flash and data EEPROM read as 0
+    pub fn erased_value(&self) -> u8 {
+        match self.family {
+            Family::L0 | Family::L1 => 0x00,
+            _ => 0xFF,
+        }
+    }
+
+    // Size of the unit the flash memory is programmed in, in bytes.
+    // Families with ECC can program a unit only once after erasing.
+    pub fn write_granularity(&self) -> usize {
+        if let Some(unit) = self.write_unit {
+            return unit;
+        }
+        match self.family {
+            Family::C0 | Family::G0 | Family::G4 | Family::L4 | Family::WB | Family::WL => 8,
+            Family::L5 | Family::U5 => 16,
+            Family::H7 => 32,
+            _ => 4,
+        }
+    }
+
+    // Checks that writing len bytes at address does not overwrite the RAM the bootloader uses
+    pub fn check_write(&self, address: u32, len: usize) -> Result<(), BootloaderError> {
+        let reserved = &self.bootloader_ram;
+        let end = address as u64 + len as u64;
+        if len > 0 && (address as u64) < reserved.end as u64 && end > reserved.start as u64 {
+            return Err(BootloaderError::BootloaderRam {
+                address: address.max(reserved.start),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} KiB flash)", self.name, self.flash.size() / KB)
+    }
+}
+
+pub fn lookup(pid: u16) -> Option<&'static Device> {
+    DEVICES.iter().find(|device| device.pid == pid)
+}
+
+const fn flash(sectors: &'static [SectorRun]) -> FlashLayout {
+    FlashLayout {
+        base: FLASH_BASE,
+        sectors,
+    }
+}
+
+const fn pages(count: u16, size: u32) -> SectorRun {
+    SectorRun { count, size }
+}
+
+const F2_F4_1M: &[SectorRun] = &[pages(4, 16 * KB), pages(1, 64 * KB), pages(7, 128 * KB)];
+const F4_2M: &[SectorRun] = &[
+    pages(4, 16 * KB),
+    pages(1, 64 * KB),
+    pages(7, 128 * KB),
+    pages(4, 16 * KB),
+    pages(1, 64 * KB),
+    pages(7, 128 * KB),
+];
+const F4_512K: &[SectorRun] = &[pages(4, 16 * KB), pages(1, 64 * KB), pages(3, 128 * KB)];
+
+const OB_F0_F1_F3: u32 = 0x1FFF_F800;
+const OB_F2_F4: u32 = 0x1FFF_C000;
+const OB_F7: u32 = 0x1FFF_0000;
+const OB_L0_L1: u32 = 0x1FF8_0000;
+const OB_G0_G4_L4_WL: u32 = 0x1FFF_7800;
+
+// most devices have a single RAM region
+#[allow(clippy::single_range_in_vec_init)]
+pub static DEVICES: &[Device] = &[
+    Device {
+        pid: 0x443,
+        family: Family::C0,
+        name: "STM32C011xx",
+        flash: flash(&[pages(16, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_1800],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x453,
+        family: Family::C0,
+        name: "STM32C031xx",
+        flash: flash(&[pages(16, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_3000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x444,
+        family: Family::F0,
+        name: "STM32F03xx4/6",
+        flash: flash(&[pages(32, KB)]),
+        ram: &[0x2000_0000..0x2000_1000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x445,
+        family: Family::F0,
+        name: "STM32F04xxx/F070x6",
+        flash: flash(&[pages(32, KB)]),
+        ram: &[0x2000_0000..0x2000_1800],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x440,
+        family: Family::F0,
+        name: "STM32F030x8/F05xxx",
+        flash: flash(&[pages(64, KB)]),
+        ram: &[0x2000_0000..0x2000_2000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x448,
+        family: Family::F0,
+        name: "STM32F07xxx",
+        flash: flash(&[pages(64, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_4000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x442,
+        family: Family::F0,
+        name: "STM32F09xxx/F030xC",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x412,
+        family: Family::F1,
+        name: "STM32F10xxx Low-density",
+        flash: flash(&[pages(32, KB)]),
+        ram: &[0x2000_0000..0x2000_2800],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0200,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x410,
+        family: Family::F1,
+        name: "STM32F10xxx Medium-density",
+        flash: flash(&[pages(128, KB)]),
+        ram: &[0x2000_0000..0x2000_5000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0200,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x414,
+        family: Family::F1,
+        name: "STM32F10xxx High-density",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0200,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x430,
+        family: Family::F1,
+        name: "STM32F10xxx XL-density",
+        flash: flash(&[pages(512, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_8000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x418,
+        family: Family::F1,
+        name: "STM32F105xx/107xx",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x420,
+        family: Family::F1,
+        name: "STM32F10xxx Medium-density value line",
+        flash: flash(&[pages(128, KB)]),
+        ram: &[0x2000_0000..0x2000_2000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0200,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x428,
+        family: Family::F1,
+        name: "STM32F10xxx High-density value line",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_0200,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x411,
+        family: Family::F2,
+        name: "STM32F2xxxx",
+        flash: flash(F2_F4_1M),
+        ram: &[0x2000_0000..0x2002_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_2000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x432,
+        family: Family::F3,
+        name: "STM32F373xx/F378xx",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1400,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x422,
+        family: Family::F3,
+        name: "STM32F302xB(C)/F303xB(C)/F358xx",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_A000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1400,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x439,
+        family: Family::F3,
+        name: "STM32F301xx/F302x4(6/8)/F318xx",
+        flash: flash(&[pages(32, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_4000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x438,
+        family: Family::F3,
+        name: "STM32F303x4(6/8)/F334xx/F328xx",
+        flash: flash(&[pages(32, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_3000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x446,
+        family: Family::F3,
+        name: "STM32F302xD(E)/F303xD(E)/F398xx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_F0_F1_F3,
+        bootloader_ram: 0x2000_0000..0x2000_1800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x413,
+        family: Family::F4,
+        name: "STM32F40xxx/41xxx",
+        flash: flash(F2_F4_1M),
+        ram: &[0x2000_0000..0x2002_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x419,
+        family: Family::F4,
+        name: "STM32F42xxx/43xxx",
+        flash: flash(F4_2M),
+        ram: &[0x2000_0000..0x2003_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x423,
+        family: Family::F4,
+        name: "STM32F401xB(C)",
+        flash: flash(&[pages(4, 16 * KB), pages(1, 64 * KB), pages(1, 128 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x433,
+        family: Family::F4,
+        name: "STM32F401xD(E)",
+        flash: flash(F4_512K),
+        ram: &[0x2000_0000..0x2001_8000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x458,
+        family: Family::F4,
+        name: "STM32F410xx",
+        flash: flash(&[pages(4, 16 * KB), pages(1, 64 * KB)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x431,
+        family: Family::F4,
+        name: "STM32F411xx",
+        flash: flash(F4_512K),
+        ram: &[0x2000_0000..0x2002_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x441,
+        family: Family::F4,
+        name: "STM32F412xx",
+        flash: flash(F2_F4_1M),
+        ram: &[0x2000_0000..0x2004_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x463,
+        family: Family::F4,
+        name: "STM32F413xx/423xx",
+        flash: flash(&[pages(4, 16 * KB), pages(1, 64 * KB), pages(11, 128 * KB)]),
+        ram: &[0x2000_0000..0x2005_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x421,
+        family: Family::F4,
+        name: "STM32F446xx",
+        flash: flash(F4_512K),
+        ram: &[0x2000_0000..0x2002_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x434,
+        family: Family::F4,
+        name: "STM32F469xx/479xx",
+        flash: flash(F4_2M),
+        ram: &[0x2000_0000..0x2006_0000],
+        option_bytes: OB_F2_F4,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x452,
+        family: Family::F7,
+        name: "STM32F72xxx/73xxx",
+        flash: flash(F4_512K),
+        ram: &[0x2000_0000..0x2004_0000],
+        option_bytes: OB_F7,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x449,
+        family: Family::F7,
+        name: "STM32F74xxx/75xxx",
+        flash: flash(&[pages(4, 32 * KB), pages(1, 128 * KB), pages(3, 256 * KB)]),
+        ram: &[0x2000_0000..0x2005_0000],
+        option_bytes: OB_F7,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x451,
+        family: Family::F7,
+        name: "STM32F76xxx/77xxx",
+        flash: flash(&[pages(4, 32 * KB), pages(1, 128 * KB), pages(7, 256 * KB)]),
+        ram: &[0x2000_0000..0x2008_0000],
+        option_bytes: OB_F7,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x466,
+        family: Family::G0,
+        name: "STM32G03xxx/04xxx",
+        flash: flash(&[pages(32, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_2000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x456,
+        family: Family::G0,
+        name: "STM32G05xxx/061xx",
+        flash: flash(&[pages(32, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_4800],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x460,
+        family: Family::G0,
+        name: "STM32G07xxx/08xxx",
+        flash: flash(&[pages(64, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_9000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x467,
+        family: Family::G0,
+        name: "STM32G0B0xx/G0B1xx/G0C1xx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2002_4000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x468,
+        family: Family::G4,
+        name: "STM32G431xx/441xx",
+        flash: flash(&[pages(64, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x469,
+        family: Family::G4,
+        name: "STM32G47xxx/48xxx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2002_0000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x479,
+        family: Family::G4,
+        name: "STM32G491xx/4A1xx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_C000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x450,
+        family: Family::H7,
+        name: "STM32H74xxx/75xxx",
+        flash: flash(&[pages(16, 128 * KB)]),
+        ram: &[
+            0x2000_0000..0x2002_0000,
+            0x2400_0000..0x2408_0000,
+            0x3000_0000..0x3004_8000,
+        ],
+        option_bytes: 0x5200_201C,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x480,
+        family: Family::H7,
+        name: "STM32H7A3xx/B3xx",
+        flash: flash(&[pages(256, 8 * KB)]),
+        ram: &[0x2000_0000..0x2002_0000, 0x2400_0000..0x2410_0000],
+        option_bytes: 0x5200_201C,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        // 128-bit flash word instead of the 256 bits of the other H7
+        write_unit: Some(16),
+    },
+    Device {
+        pid: 0x483,
+        family: Family::H7,
+        name: "STM32H72xxx/73xxx",
+        flash: flash(&[pages(8, 128 * KB)]),
+        ram: &[0x2000_0000..0x2002_0000, 0x2400_0000..0x2405_0000],
+        option_bytes: 0x5200_201C,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x457,
+        family: Family::L0,
+        name: "STM32L01xxx/02xxx",
+        flash: flash(&[pages(128, 128)]),
+        ram: &[0x2000_0000..0x2000_0800],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_0800,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x425,
+        family: Family::L0,
+        name: "STM32L031xx/041xx",
+        flash: flash(&[pages(256, 128)]),
+        ram: &[0x2000_0000..0x2000_2000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x417,
+        family: Family::L0,
+        name: "STM32L05xxx/06xxx",
+        flash: flash(&[pages(512, 128)]),
+        ram: &[0x2000_0000..0x2000_2000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x447,
+        family: Family::L0,
+        name: "STM32L07xxx/08xxx",
+        flash: flash(&[pages(1536, 128)]),
+        ram: &[0x2000_0000..0x2000_5000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x416,
+        family: Family::L1,
+        name: "STM32L1xxx6(8/B)",
+        flash: flash(&[pages(512, 256)]),
+        ram: &[0x2000_0000..0x2000_4000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x429,
+        family: Family::L1,
+        name: "STM32L1xxx6(8/B)A",
+        flash: flash(&[pages(512, 256)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x427,
+        family: Family::L1,
+        name: "STM32L1xxxC",
+        flash: flash(&[pages(1024, 256)]),
+        ram: &[0x2000_0000..0x2000_8000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x436,
+        family: Family::L1,
+        name: "STM32L1xxxD",
+        flash: flash(&[pages(1536, 256)]),
+        ram: &[0x2000_0000..0x2000_C000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x437,
+        family: Family::L1,
+        name: "STM32L1xxxE",
+        flash: flash(&[pages(2048, 256)]),
+        ram: &[0x2000_0000..0x2001_4000],
+        option_bytes: OB_L0_L1,
+        bootloader_ram: 0x2000_0000..0x2000_1000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x464,
+        family: Family::L4,
+        name: "STM32L41xxx/42xxx",
+        flash: flash(&[pages(64, 2 * KB)]),
+        ram: &[0x2000_0000..0x2000_A000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x435,
+        family: Family::L4,
+        name: "STM32L43xxx/44xxx",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x462,
+        family: Family::L4,
+        name: "STM32L45xxx/46xxx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2002_8000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x415,
+        family: Family::L4,
+        name: "STM32L47xxx/48xxx",
+        flash: flash(&[pages(512, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_8000, 0x1000_0000..0x1000_8000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x461,
+        family: Family::L4,
+        name: "STM32L496xx/4A6xx",
+        flash: flash(&[pages(512, 2 * KB)]),
+        ram: &[0x2000_0000..0x2005_0000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x470,
+        family: Family::L4,
+        name: "STM32L4Rxx/4Sxx",
+        flash: flash(&[pages(512, 4 * KB)]),
+        ram: &[0x2000_0000..0x200A_0000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_3000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x472,
+        family: Family::L5,
+        name: "STM32L552xx/562xx",
+        flash: flash(&[pages(256, 2 * KB)]),
+        ram: &[0x2000_0000..0x2004_0000],
+        option_bytes: 0x4002_2040,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x482,
+        family: Family::U5,
+        name: "STM32U575xx/585xx",
+        flash: flash(&[pages(256, 8 * KB)]),
+        ram: &[0x2000_0000..0x200C_0000],
+        option_bytes: 0x4002_2040,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x495,
+        family: Family::WB,
+        name: "STM32WB5xxx/35xx",
+        flash: flash(&[pages(256, 4 * KB)]),
+        ram: &[0x2000_0000..0x2003_0000],
+        option_bytes: 0x1FFF_8000,
+        bootloader_ram: 0x2000_0000..0x2000_4000,
+        write_unit: None,
+    },
+    Device {
+        pid: 0x497,
+        family: Family::WL,
+        name: "STM32WLE5xx/WL55xx",
+        flash: flash(&[pages(128, 2 * KB)]),
+        ram: &[0x2000_0000..0x2001_0000],
+        option_bytes: OB_G0_G4_L4_WL,
+        bootloader_ram: 0x2000_0000..0x2000_2000,
+        write_unit: None,
+    },
+];
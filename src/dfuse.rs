@@ -0,0 +1,116 @@
+// DfuSe (.dfu) container reader, see UM0391 "DfuSe File Format Specification".
+// A file is a prefix, a number of targets with their image elements and the DFU suffix.
+use crate::crc;
+use crate::image::{FileFormat, MemoryImage, Segment};
+use crate::BootloaderError;
+
+const PREFIX_SIGNATURE: &[u8] = b"DfuSe";
+const TARGET_SIGNATURE: &[u8] = b"Target";
+const SUFFIX_SIGNATURE: &[u8] = b"UFD";
+
+const PREFIX_SIZE: usize = 11;
+const TARGET_PREFIX_SIZE: usize = 274;
+const ELEMENT_HEADER_SIZE: usize = 8;
+const SUFFIX_SIZE: usize = 16;
+
+// Alternate setting of the internal flash in the STM32 DFU bootloaders, the
+// others address the option bytes, OTP or other memories
+const FLASH_ALTERNATE_SETTING: u8 = 0;
+
+fn error(reason: &str) -> BootloaderError {
+    BootloaderError::InvalidFile {
+        format: FileFormat::DfuSe,
+        reason: reason.to_string(),
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, BootloaderError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| error("truncated file"))
+}
+
+// Checks the suffix CRC and returns the elements of all targets as segments.
+// Only targets for the internal flash are accepted, the UART bootloader writes the
+// other memories differently.
+pub fn parse(data: &[u8]) -> Result<MemoryImage, BootloaderError> {
+    if data.len() < PREFIX_SIZE + SUFFIX_SIZE || !data.starts_with(PREFIX_SIGNATURE) {
+        return Err(error("not a DfuSe file"));
+    }
+
+    let suffix = &data[data.len() - SUFFIX_SIZE..];
+    if &suffix[8..11] != SUFFIX_SIGNATURE || suffix[11] as usize != SUFFIX_SIZE {
+        return Err(error("missing DFU suffix"));
+    }
+    let expected_crc = u32_at(suffix, 12)?;
+    let crc = crc::dfu_crc32(&data[..data.len() - 4]);
+    if crc != expected_crc {
+        return Err(error(&format!(
+            "CRC mismatch: expected {:#010x}, got {:#010x}",
+            expected_crc, crc
+        )));
+    }
+
+    let version = data[5];
+    if version != 0x01 {
+        return Err(error(&format!("unsupported version {}", version)));
+    }
+    let image_size = u32_at(data, 6)? as usize;
+    if image_size != data.len() - SUFFIX_SIZE {
+        return Err(error("image size does not match the file size"));
+    }
+    let image = &data[..image_size];
+    let targets = data[10];
+
+    let mut segments = Vec::new();
+    let mut offset = PREFIX_SIZE;
+    for _ in 0..targets {
+        let target = image
+            .get(offset..offset + TARGET_PREFIX_SIZE)
+            .ok_or_else(|| error("truncated target"))?;
+        if !target.starts_with(TARGET_SIGNATURE) {
+            return Err(error("invalid target signature"));
+        }
+        let alternate_setting = target[6];
+        let name = target[11..266].split(|&b| b == 0).next().unwrap_or(&[]);
+        let target_size = u32_at(target, 266)? as usize;
+        let elements = u32_at(target, 270)?;
+        log::debug!(
+            "DfuSe target {} \"{}\": {} elements",
+            alternate_setting,
+            String::from_utf8_lossy(name),
+            elements
+        );
+        if alternate_setting != FLASH_ALTERNATE_SETTING {
+            return Err(error(&format!(
+                "target {} \"{}\" is not the internal flash",
+                alternate_setting,
+                String::from_utf8_lossy(name)
+            )));
+        }
+        offset += TARGET_PREFIX_SIZE;
+        let target_end = offset + target_size;
+        if target_end > image.len() {
+            return Err(error("truncated target"));
+        }
+
+        for _ in 0..elements {
+            let address = u32_at(&image[..target_end], offset)?;
+            let size = u32_at(&image[..target_end], offset + 4)? as usize;
+            offset += ELEMENT_HEADER_SIZE;
+            let element = image[..target_end]
+                .get(offset..offset + size)
+                .ok_or_else(|| error("element exceeds target"))?;
+            segments.push(Segment {
+                address,
+                data: element.to_vec(),
+            });
+            offset += size;
+        }
+        if offset != target_end {
+            return Err(error("target size does not match its elements"));
+        }
+    }
+
+    MemoryImage::from_segments(FileFormat::DfuSe, segments, None)
+}
@@ -0,0 +1,81 @@
+// ELF32 reader for ARM executables
+// https://refspecs.linuxfoundation.org/elf/elf.pdf
+use crate::image::{FileFormat, MemoryImage, Segment};
+use crate::BootloaderError;
+
+pub const MAGIC: &[u8] = b"\x7FELF";
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+
+fn error(reason: &str) -> BootloaderError {
+    BootloaderError::InvalidFile {
+        format: FileFormat::Elf,
+        reason: reason.to_string(),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, BootloaderError> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| error("truncated file"))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, BootloaderError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| error("truncated file"))
+}
+
+// Extracts the PT_LOAD segments at their physical (load) address.
+// The part of a segment which is not in the file (.bss) is not written.
+// Segments loaded outside of flash (RAM functions, CCM, ITCM) are kept,
+// flashing crops them with MemoryImage::crop_to_flash.
+pub fn parse(data: &[u8]) -> Result<MemoryImage, BootloaderError> {
+    if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+        return Err(error("not an ELF file"));
+    }
+    if data[4] != ELFCLASS32 || data[5] != ELFDATA2LSB {
+        return Err(error("not a 32 bit little endian ELF file"));
+    }
+    let machine = u16_at(data, 18)?;
+    if machine != EM_ARM {
+        return Err(error(&format!("unsupported machine {}", machine)));
+    }
+    let entry = u32_at(data, 24)?;
+    let phoff = u32_at(data, 28)? as usize;
+    let phentsize = u16_at(data, 42)? as usize;
+    let phnum = u16_at(data, 44)? as usize;
+    if phentsize < PROGRAM_HEADER_SIZE {
+        return Err(error("invalid program header size"));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if u32_at(data, header)? != PT_LOAD {
+            continue;
+        }
+        let offset = u32_at(data, header + 4)? as usize;
+        let paddr = u32_at(data, header + 12)?;
+        let filesz = u32_at(data, header + 16)? as usize;
+        if filesz == 0 {
+            log::trace!("skipping segment without data at {:#010X}", paddr);
+            continue;
+        }
+        let segment_data = data
+            .get(offset..offset + filesz)
+            .ok_or_else(|| error("segment exceeds file"))?;
+        segments.push(Segment {
+            address: paddr,
+            data: segment_data.to_vec(),
+        });
+    }
+
+    MemoryImage::from_segments(FileFormat::Elf, segments, Some(entry))
+}
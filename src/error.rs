@@ -0,0 +1,322 @@
+use std::fmt;
+use std::io;
+
+use crate::device::{self, ExpectedChip};
+use crate::image::FileFormat;
+use crate::Command;
+
+// Step of a command after which the bootloader answered unexpectedly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // Command byte and its complement
+    Command,
+    // Start address and checksum
+    Address,
+    // Number of bytes, memory area size
+    Length,
+    // Data, page or sector numbers
+    Data,
+    // Response sent by the device
+    Response,
+    // Operation the device performs before its final ACK (e.g. erase, option byte programming)
+    Completion,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Command => "command",
+            Stage::Address => "address",
+            Stage::Length => "length",
+            Stage::Data => "data",
+            Stage::Response => "response",
+            Stage::Completion => "completion",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug)]
+pub enum BootloaderError {
+    // The device answered with NACK
+    Nack {
+        command: Command,
+        stage: Stage,
+        address: Option<u32>,
+    },
+    // The device answered with neither ACK nor NACK, or sent a response with a wrong checksum
+    UnexpectedByte {
+        command: Command,
+        stage: Stage,
+        address: Option<u32>,
+        byte: u8,
+    },
+    // The device did not answer in time
+    Timeout {
+        command: Command,
+        stage: Stage,
+        address: Option<u32>,
+    },
+    // The length of the data can not be sent with this command
+    InvalidLength {
+        command: Command,
+        len: usize,
+    },
+    // The address does not meet the alignment required by this command
+    Unaligned {
+        command: Command,
+        address: u32,
+    },
+    // Verification found different data on the device
+    Mismatch {
+        address: u32,
+        expected: u8,
+        actual: u8,
+    },
+    // Get Checksum disagrees with data that reads back correctly, the checksums
+    // of the device can not be trusted
+    ChecksumMismatch {
+        address: u32,
+        len: usize,
+        expected: u32,
+        actual: u32,
+    },
+    // The bootloader does not support the command
+    Unsupported(Command),
+    // The product ID is not in the device table
+    UnknownDevice(u16),
+    // The programming unit at address would have to be programmed twice
+    PartialProgramUnit {
+        address: u32,
+        granularity: usize,
+    },
+    // The device is not the expected chip
+    WrongChip {
+        expected: ExpectedChip,
+        pid: u16,
+    },
+    // The image does not fit into the flash memory of the device. The device table lists
+    // the largest variant of a product ID, smaller variants may end before flash_end.
+    ImageTooLarge {
+        end: u64,
+        flash_end: u32,
+    },
+    // A segment of the image starts outside the flash memory of the device
+    OutsideFlash {
+        address: u32,
+        flash_base: u32,
+        flash_end: u32,
+    },
+    // The data overlaps the RAM used by the bootloader
+    BootloaderRam {
+        address: u32,
+    },
+    // The data overlaps data already in the memory image
+    Overlap {
+        address: u32,
+    },
+    // The data does not fit into the 32-bit address space
+    AddressOverflow {
+        address: u32,
+        len: usize,
+    },
+    // A firmware file could not be parsed
+    InvalidFile {
+        format: FileFormat,
+        reason: String,
+    },
+    // The vector table at the start of the image does not fit the device,
+    // the firmware is probably linked for a different address
+    InvalidVectorTable {
+        address: u32,
+        reason: String,
+    },
+    Io(io::Error),
+}
+
+impl BootloaderError {
+    // Wraps an I/O error which occurred while waiting for the device
+    pub(crate) fn from_io(
+        e: io::Error,
+        command: Command,
+        stage: Stage,
+        address: Option<u32>,
+    ) -> Self {
+        if e.kind() == io::ErrorKind::TimedOut {
+            BootloaderError::Timeout {
+                command,
+                stage,
+                address,
+            }
+        } else {
+            BootloaderError::Io(e)
+        }
+    }
+}
+
+fn fmt_address(f: &mut fmt::Formatter<'_>, address: &Option<u32>) -> fmt::Result {
+    match address {
+        Some(address) => write!(f, " at {:#010X}", address),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for BootloaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootloaderError::Nack {
+                command,
+                stage,
+                address,
+            } => {
+                write!(f, "{}: received NACK after {}", command, stage)?;
+                fmt_address(f, address)
+            }
+            BootloaderError::UnexpectedByte {
+                command,
+                stage,
+                address,
+                byte,
+            } => {
+                write!(
+                    f,
+                    "{}: unexpected byte {:#04x} after {}",
+                    command, byte, stage
+                )?;
+                fmt_address(f, address)
+            }
+            BootloaderError::Timeout {
+                command,
+                stage,
+                address,
+            } => {
+                write!(f, "{}: timed out after {}", command, stage)?;
+                fmt_address(f, address)
+            }
+            BootloaderError::InvalidLength { command, len } => {
+                write!(f, "{}: invalid length {}", command, len)
+            }
+            BootloaderError::Unaligned { command, address } => {
+                write!(f, "{}: address {:#010X} is not aligned", command, address)
+            }
+            BootloaderError::Mismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Mismatch at {:#010X}: expected {:#04x}, got {:#04x}",
+                address, expected, actual
+            ),
+            BootloaderError::ChecksumMismatch {
+                address,
+                len,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum of {} bytes at {:#010X} is {:#010x} instead of {:#010x} \
+                 although the data reads back correctly",
+                len, address, actual, expected
+            ),
+            BootloaderError::Unsupported(command) => {
+                write!(f, "{} is not supported by the bootloader", command)
+            }
+            BootloaderError::UnknownDevice(pid) => write!(f, "Unknown device {:#05X}", pid),
+            BootloaderError::PartialProgramUnit {
+                address,
+                granularity,
+            } => write!(
+                f,
+                "The {} byte programming unit at {:#010X} would be programmed twice",
+                granularity, address
+            ),
+            BootloaderError::WrongChip { expected, pid } => {
+                write!(f, "Expected {}, found {:#05X}", expected, pid)?;
+                match device::lookup(*pid) {
+                    Some(device) => write!(f, " {}", device),
+                    None => Ok(()),
+                }
+            }
+            BootloaderError::ImageTooLarge { end, flash_end } => write!(
+                f,
+                "Image ends at {:#010X}, after the end of the flash memory at {:#010X} \
+                 of the largest variant of the device",
+                end, flash_end
+            ),
+            BootloaderError::OutsideFlash {
+                address,
+                flash_base,
+                flash_end,
+            } => write!(
+                f,
+                "Data at {:#010X} is outside the flash memory at {:#010X}..{:#010X}",
+                address, flash_base, flash_end
+            ),
+            BootloaderError::BootloaderRam { address } => write!(
+                f,
+                "Data at {:#010X} overlaps the RAM used by the bootloader",
+                address
+            ),
+            BootloaderError::Overlap { address } => {
+                write!(f, "Data at {:#010X} overlaps the image", address)
+            }
+            BootloaderError::AddressOverflow { address, len } => write!(
+                f,
+                "{} bytes at {:#010X} exceed the 32-bit address space",
+                len, address
+            ),
+            BootloaderError::InvalidFile { format, reason } => {
+                write!(f, "Invalid {} file: {}", format, reason)
+            }
+            BootloaderError::InvalidVectorTable { address, reason } => {
+                write!(f, "Invalid vector table at {:#010X}: {}", address, reason)
+            }
+            BootloaderError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BootloaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BootloaderError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BootloaderError {
+    fn from(e: io::Error) -> Self {
+        BootloaderError::Io(e)
+    }
+}
+
+impl From<BootloaderError> for io::Error {
+    fn from(e: BootloaderError) -> Self {
+        let kind = match e {
+            BootloaderError::Io(e) => return e,
+            BootloaderError::Timeout { .. } => io::ErrorKind::TimedOut,
+            BootloaderError::InvalidLength { .. }
+            | BootloaderError::Unaligned { .. }
+            | BootloaderError::Overlap { .. }
+            | BootloaderError::AddressOverflow { .. }
+            | BootloaderError::PartialProgramUnit { .. } => io::ErrorKind::InvalidInput,
+            BootloaderError::Mismatch { .. }
+            | BootloaderError::ChecksumMismatch { .. }
+            | BootloaderError::InvalidFile { .. }
+            | BootloaderError::InvalidVectorTable { .. } => io::ErrorKind::InvalidData,
+            BootloaderError::WrongChip { .. }
+            | BootloaderError::ImageTooLarge { .. }
+            | BootloaderError::OutsideFlash { .. }
+            | BootloaderError::BootloaderRam { .. } => io::ErrorKind::InvalidInput,
+            BootloaderError::Unsupported(_) | BootloaderError::UnknownDevice(_) => {
+                io::ErrorKind::Unsupported
+            }
+            BootloaderError::Nack { .. } | BootloaderError::UnexpectedByte { .. } => {
+                io::ErrorKind::Other
+            }
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -1,9 +1,13 @@
+use std::io::{Read, Write};
 use std::{thread::sleep, time::Duration};
 
 use crate::{
-    extended_erase_special,
+    device::{self, Device, ExpectedChip},
+    get_id,
     helper::{connect_port, toggle_reset, GpioPin},
-    read_memory, verify_memory, write_memory, SpecialEraseType,
+    plan::VerifyMethod,
+    read_memory, verify_image, write_image, Bootloader, BootloaderError, Command, EraseStrategy,
+    FlashPlan, MemoryImage, Stage, VectorCheck, WriteOptions,
 };
 
 #[derive(Debug, Clone)]
@@ -13,6 +17,12 @@ pub struct FlashConfig {
     pub boot_pin: u32,
     pub reset_pin: u32,
     pub address: u32,
+    pub erase: EraseStrategy,
+    pub write: WriteOptions,
+    // Abort before erasing if the device is a different chip
+    pub expected_chip: Option<ExpectedChip>,
+    // Check the vector table of the image before erasing
+    pub check_vectors: VectorCheck,
 }
 
 impl<T> From<T> for FlashConfig
@@ -35,10 +45,125 @@ impl Default for FlashConfig {
             boot_pin: 9,
             reset_pin: 8,
             address: 0x08000000,
+            erase: EraseStrategy::default(),
+            write: WriteOptions::default(),
+            expected_chip: None,
+            check_vectors: VectorCheck::default(),
         }
     }
 }
 
+// Outcome of prepare_flash, what is left to write
+#[derive(Debug)]
+pub struct PreparedFlash {
+    // Write options adapted to the device
+    pub options: WriteOptions,
+    // With ChangedSectorsOnly only the changed part of the image is written
+    pub changed: Option<MemoryImage>,
+    // The erase timed out, the port has to be reconnected before writing.
+    // Some bootloaders do not answer a long erase in time although it succeeds.
+    pub reconnect: Option<BootloaderError>,
+}
+
+// Checks the device and the image, then erases what the image needs.
+// Errors of the checks and of the comparison are returned before anything is erased,
+// of the erase only a timeout waiting for its final ACK is not an error.
+pub fn prepare_flash<T: Read + Write>(
+    port: &mut T,
+    image: &MemoryImage,
+    config: &FlashConfig,
+) -> Result<PreparedFlash, BootloaderError> {
+    let mut bootloader = Bootloader::attach(port)?;
+    let (_, options) = check(&mut bootloader, image, config)?;
+    let mut changed = None;
+    if config.erase == EraseStrategy::ChangedSectorsOnly {
+        let diff = bootloader.diff_sectors(image, options)?;
+        log::info!(
+            "{} sectors changed, skipping {} unchanged sectors",
+            diff.changed.len(),
+            diff.unchanged.len()
+        );
+        changed = Some(diff.changed_image(image)?);
+    }
+    log::info!("Erasing");
+    let reconnect =
+        match bootloader.erase_for_image(config.erase, changed.as_ref().unwrap_or(image)) {
+            Ok(()) => None,
+            Err(
+                e @ BootloaderError::Timeout {
+                    command: Command::Erase | Command::ExtendedErase,
+                    stage: Stage::Data,
+                    ..
+                },
+            ) => Some(e),
+            Err(e) => return Err(e),
+        };
+    Ok(PreparedFlash {
+        options,
+        changed,
+        reconnect,
+    })
+}
+
+// Runs the checks of prepare_flash and plans flashing the image,
+// only Get and Get ID are sent to the device
+pub fn plan_flash<T: Read + Write>(
+    port: &mut T,
+    image: &MemoryImage,
+    config: &FlashConfig,
+) -> Result<FlashPlan, BootloaderError> {
+    let mut bootloader = Bootloader::attach(port)?;
+    let (device, options) = check(&mut bootloader, image, config)?;
+    let verify = VerifyMethod::for_commands(bootloader.commands());
+    FlashPlan::new(
+        image,
+        device,
+        config.erase,
+        options,
+        Some(verify),
+        config.baud_rate,
+    )
+}
+
+// Checks the device and the image, returns the device and the write options for it
+fn check<T: Read + Write>(
+    bootloader: &mut Bootloader<T>,
+    image: &MemoryImage,
+    config: &FlashConfig,
+) -> Result<(Option<&'static Device>, WriteOptions), BootloaderError> {
+    let device = bootloader.check_target(config.expected_chip, image)?;
+    config.check_vectors.check(image, device)?;
+    let mut options = config.write;
+    if let Some(device) = device {
+        options.apply_device(device);
+    }
+    Ok((device, options))
+}
+
+// Plans writing the image with write_image, which neither erases nor verifies.
+// The checks of prepare_flash do not apply, so RAM can be written as well.
+// Only Get ID is sent to the device.
+pub fn plan_write<T: Read + Write>(
+    port: &mut T,
+    image: &MemoryImage,
+    options: WriteOptions,
+    baud_rate: u32,
+) -> Result<FlashPlan, BootloaderError> {
+    let device = device::lookup(get_id(port)?);
+    let mut options = options;
+    if let Some(device) = device {
+        options.apply_device(device);
+    }
+    FlashPlan::new(
+        image,
+        device,
+        EraseStrategy::None,
+        options,
+        Some(VerifyMethod::Off),
+        baud_rate,
+    )
+}
+
 pub struct Flasher {
     config: FlashConfig,
     port: Option<Box<dyn serialport::SerialPort>>,
@@ -56,7 +181,7 @@ impl Flasher {
         }
     }
 
-    pub fn open(config: FlashConfig) -> Result<Self, std::io::Error> {
+    pub fn open(config: FlashConfig) -> Result<Self, BootloaderError> {
         log::debug!("Setting boot pin {}", config.boot_pin);
         let mut gpio_boot = GpioPin::new(config.boot_pin)?;
         gpio_boot.set_value(1)?;
@@ -77,27 +202,40 @@ impl Flasher {
         })
     }
 
-    pub fn flash(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
-        let mut port = self
+    // Flashes data at the configured address
+    pub fn flash(&mut self, data: &[u8]) -> Result<(), BootloaderError> {
+        self.flash_image(&MemoryImage::from_binary(
+            self.config.address,
+            data.to_vec(),
+        ))
+    }
+
+    // Erases, writes and verifies all segments of the image
+    pub fn flash_image(&mut self, image: &MemoryImage) -> Result<(), BootloaderError> {
+        let port = self
             .port
             .as_mut()
             .ok_or(std::io::Error::other("Port not open"))?;
-        // Note: this might time out for some reason, it does succeed anyway
-        let res = extended_erase_special(port, SpecialEraseType::MassErase);
-        if let Err(e) = res {
+        let prepared = prepare_flash(port, image, &self.config)?;
+        if let Some(e) = &prepared.reconnect {
             log::debug!("Reconnect after erase: {:?}", e);
             // close current port
             drop(self.port.take());
 
             toggle_reset(&mut self.gpio_reset)?;
             self.port = Some(connect_port(&self.config.port, self.config.baud_rate)?);
-            port = self.port.as_mut().unwrap();
         }
+        let port = self.port.as_mut().unwrap();
 
-        log::debug!("Flashing {} bytes to {:#010X}", data.len(), self.config.address);
-        write_memory(port, self.config.address, data)?;
+        let write = prepared.changed.as_ref().unwrap_or(image);
+        log::debug!(
+            "Flashing {} bytes in {} segments",
+            write.len(),
+            write.segments().len()
+        );
+        write_image(port, write, prepared.options)?;
         log::debug!("Writing done, verifying");
-        verify_memory(port, self.config.address, data)?;
+        verify_image(port, image, prepared.options)?;
         log::debug!("Flash Successful");
         sleep(Duration::from_millis(100));
         Ok(())
@@ -111,7 +249,28 @@ impl Flasher {
         e1.and(e2)
     }
 
-    pub fn read_memory(&mut self, address: u32, dst_data: &mut [u8]) -> Result<(), std::io::Error> {
+    // Plans flashing the image with the configuration, see plan_flash
+    pub fn plan(&mut self, image: &MemoryImage) -> Result<FlashPlan, BootloaderError> {
+        let port = self
+            .port
+            .as_mut()
+            .ok_or(std::io::Error::other("Port not open"))?;
+        plan_flash(port, image, &self.config)
+    }
+
+    pub fn verify_image(&mut self, image: &MemoryImage) -> Result<(), BootloaderError> {
+        let port = self
+            .port
+            .as_mut()
+            .ok_or(std::io::Error::other("Port not open"))?;
+        verify_image(port, image, self.config.write)
+    }
+
+    pub fn read_memory(
+        &mut self,
+        address: u32,
+        dst_data: &mut [u8],
+    ) -> Result<(), BootloaderError> {
         let port = self
             .port
             .as_mut()
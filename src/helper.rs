@@ -3,40 +3,15 @@ use std::{thread::sleep, time::Duration};
 use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
 use serialport::prelude::*;
 
-use crate::{extended_erase_special, flasher::FlashConfig, write_memory, SpecialEraseType};
+use crate::{BootloaderError, FlashConfig, Flasher};
 
-pub fn full_process_flash(data: &[u8], conf: &FlashConfig) -> Result<(), std::io::Error> {
-    log::debug!("Setting boot pin {}", conf.boot_pin);
-    let mut gpio_boot = GpioPin::new(conf.boot_pin)?;
-    gpio_boot.set_value(1)?;
-    sleep(Duration::from_millis(100));
-
-    log::debug!("Toggling reset pin {}", conf.reset_pin);
-    let mut gpio_reset = GpioPin::new(conf.reset_pin)?;
-    toggle_reset(&mut gpio_reset)?;
-
-    let mut port = connect_port(&conf.port, conf.baud_rate)?;
-    log::debug!("Connected on {}", conf.port);
-
-    // Note: this might time out for some reason, it does succeed anyway
-    let res = extended_erase_special(&mut port, SpecialEraseType::MassErase);
-    if let Err(e) = res {
-        log::debug!("Reconnect after erase: {:?}", e);
-        // close current port
-        drop(port);
-
-        toggle_reset(&mut gpio_reset)?;
-        port = connect_port(&conf.port, conf.baud_rate)?;
-    }
-
-    log::debug!("Flashing {} bytes to {}", data.len(), conf.address);
-    write_memory(&mut port, conf.address, data)?;
-    log::debug!("Flash Complete");
-    sleep(Duration::from_millis(100));
-
-    log::debug!("Resetting boot pin");
-    gpio_boot.set_value(0)?;
-    toggle_reset(&mut gpio_reset)?;
+// Flashes data at the configured address and verifies it, see Flasher::flash.
+// A flash that did not take fails with BootloaderError::Mismatch. Callers which need
+// an io::Error convert with io::Error::from.
+pub fn full_process_flash(data: &[u8], conf: &FlashConfig) -> Result<(), BootloaderError> {
+    let mut flasher = Flasher::open(conf.clone())?;
+    flasher.flash(data)?;
+    flasher.reset()?;
 
     log::info!("Done flashing");
     Ok(())
@@ -51,9 +26,25 @@ pub fn toggle_reset(gpio_reset: &mut GpioPin) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+// Timeout of the commands once connected, erasing can take long
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Opens the port and synchronises with the bootloader
 pub fn connect_port(
     port_name: &str,
     baud_rate: u32,
+) -> Result<Box<dyn serialport::SerialPort>, std::io::Error> {
+    let mut port = open_port(port_name, baud_rate)?;
+    synchronise(&mut port)?;
+    port.set_timeout(COMMAND_TIMEOUT)?;
+    Ok(port)
+}
+
+// Opens the port with the framing of the bootloader, 8 data bits and even parity.
+// The timeout is short for synchronising.
+pub fn open_port(
+    port_name: &str,
+    baud_rate: u32,
 ) -> Result<Box<dyn serialport::SerialPort>, std::io::Error> {
     let s = SerialPortSettings {
         baud_rate,
@@ -66,14 +57,16 @@ pub fn connect_port(
 
     let mut port = serialport::posix::TTYPort::open(std::path::Path::new(port_name), &s)?;
     port.set_exclusive(true)?;
+    Ok(Box::new(port))
+}
 
+// Sends the hello byte until the bootloader answers, it detects the baud rate from it
+pub fn synchronise<T: std::io::Read + std::io::Write>(port: &mut T) -> Result<(), std::io::Error> {
     let mut last_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "Failed to connect");
     for _ in 0..10 {
-        if let Err(e) = crate::hello(&mut port) {
-            last_err = e;
-        } else {
-            port.set_timeout(Duration::from_secs(20))?;
-            return Ok(Box::new(port));
+        match crate::hello(port) {
+            Ok(()) => return Ok(()),
+            Err(e) => last_err = e.into(),
         }
         sleep(Duration::from_millis(100));
     }
@@ -96,7 +89,7 @@ pub enum GpioPin {
 }
 
 fn cdev_error_to_io_error(e: gpio_cdev::Error) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
+    std::io::Error::other(format!("{:?}", e))
 }
 
 impl GpioPin {
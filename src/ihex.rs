@@ -0,0 +1,94 @@
+// Intel HEX reader
+// https://developer.arm.com/documentation/ka003292/latest
+use crate::image::{decode_hex, FileFormat, MemoryImage, Segment};
+use crate::BootloaderError;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+fn error(line: usize, reason: &str) -> BootloaderError {
+    BootloaderError::InvalidFile {
+        format: FileFormat::IntelHex,
+        reason: format!("line {}: {}", line, reason),
+    }
+}
+
+// Parses the records of an Intel HEX file.
+// Data records are placed using the last extended segment or linear address record,
+// the start address records set the entry point.
+pub fn parse(text: &str) -> Result<MemoryImage, BootloaderError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut base: u32 = 0;
+    let mut entry = None;
+    let mut end_of_file = false;
+
+    for (i, record) in text.lines().enumerate() {
+        let line = i + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        if end_of_file {
+            return Err(error(line, "data after end of file record"));
+        }
+        let bytes = record
+            .strip_prefix(':')
+            .ok_or_else(|| error(line, "missing start code"))?;
+        let bytes = decode_hex(bytes).ok_or_else(|| error(line, "invalid hex digits"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error(line, "invalid record length"));
+        }
+        if bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != 0 {
+            return Err(error(line, "checksum mismatch"));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let record_type = bytes[3];
+        let data = &bytes[4..bytes.len() - 1];
+        match (record_type, data.len()) {
+            (DATA, _) => {
+                let address = base.wrapping_add(offset as u32);
+                match segments.last_mut() {
+                    Some(last) if last.end() == address as u64 => last.data.extend_from_slice(data),
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            (END_OF_FILE, 0) => end_of_file = true,
+            (EXTENDED_SEGMENT_ADDRESS, 2) => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            (START_SEGMENT_ADDRESS, 4) => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                entry = Some((cs << 4) + ip);
+            }
+            (EXTENDED_LINEAR_ADDRESS, 2) => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            (START_LINEAR_ADDRESS, 4) => {
+                entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            (END_OF_FILE..=START_LINEAR_ADDRESS, _) => {
+                return Err(error(line, "invalid record length"));
+            }
+            _ => {
+                return Err(error(
+                    line,
+                    &format!("unknown record type {:#04x}", record_type),
+                ))
+            }
+        }
+    }
+    if !end_of_file {
+        return Err(error(text.lines().count(), "missing end of file record"));
+    }
+
+    MemoryImage::from_segments(FileFormat::IntelHex, segments, entry)
+}
@@ -0,0 +1,411 @@
+// Firmware files read into sparse memory images
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::device::Device;
+use crate::{dfuse, elf, ihex, srec, BootloaderError};
+
+// Code region of the Cortex-M memory map, holds the flash, system memory and option bytes
+const CODE_REGION_END: u32 = 0x2000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    // Raw binary, placed at the address given by the caller
+    Binary,
+    IntelHex,
+    SRecord,
+    Elf,
+    DfuSe,
+}
+
+impl FileFormat {
+    // Guesses the format from the file extension, unknown extensions are read as raw binary
+    pub fn from_path(path: &Path) -> FileFormat {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => FileFormat::IntelHex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => FileFormat::SRecord,
+            Some("elf" | "axf" | "out") => FileFormat::Elf,
+            Some("dfu") => FileFormat::DfuSe,
+            _ => FileFormat::Binary,
+        }
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileFormat::Binary => "binary",
+            FileFormat::IntelHex => "Intel HEX",
+            FileFormat::SRecord => "S-record",
+            FileFormat::Elf => "ELF",
+            FileFormat::DfuSe => "DfuSe",
+        };
+        f.write_str(name)
+    }
+}
+
+// Contiguous data starting at address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    // First address after the segment
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+// Sparse memory contents made of segments which are ordered by address and never overlap.
+// Adjacent segments are joined.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryImage {
+    segments: Vec<Segment>,
+    // Start address if the file contains one
+    pub entry: Option<u32>,
+}
+
+impl MemoryImage {
+    pub fn new() -> Self {
+        MemoryImage::default()
+    }
+
+    pub fn from_binary(address: u32, data: Vec<u8>) -> Self {
+        MemoryImage {
+            segments: vec![Segment { address, data }],
+            entry: None,
+        }
+    }
+
+    // Builds the image of a parsed file, overlapping segments are an error in the file
+    pub(crate) fn from_segments(
+        format: FileFormat,
+        segments: Vec<Segment>,
+        entry: Option<u32>,
+    ) -> Result<Self, BootloaderError> {
+        let mut image = MemoryImage {
+            segments: Vec::with_capacity(segments.len()),
+            entry,
+        };
+        for segment in segments {
+            image.insert(segment.address, &segment.data).map_err(|e| {
+                BootloaderError::InvalidFile {
+                    format,
+                    reason: e.to_string(),
+                }
+            })?;
+        }
+        Ok(image)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    // Adds data at address, fails if any of it is already part of the image
+    pub fn insert(&mut self, address: u32, data: &[u8]) -> Result<(), BootloaderError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = address as u64 + data.len() as u64;
+        if end > 1 << 32 {
+            return Err(BootloaderError::AddressOverflow {
+                address,
+                len: data.len(),
+            });
+        }
+        // index of the first segment after address
+        let index = self
+            .segments
+            .partition_point(|segment| segment.address <= address);
+        if let Some(previous) = index.checked_sub(1).map(|i| &self.segments[i]) {
+            if previous.end() > address as u64 {
+                return Err(BootloaderError::Overlap { address });
+            }
+        }
+        if let Some(next) = self.segments.get(index) {
+            if (next.address as u64) < end {
+                return Err(BootloaderError::Overlap {
+                    address: next.address,
+                });
+            }
+        }
+
+        let joins_previous = index > 0 && self.segments[index - 1].end() == address as u64;
+        let joins_next = self
+            .segments
+            .get(index)
+            .is_some_and(|next| next.address as u64 == end);
+        match (joins_previous, joins_next) {
+            (true, true) => {
+                let next = self.segments.remove(index);
+                let previous = &mut self.segments[index - 1];
+                previous.data.extend_from_slice(data);
+                previous.data.extend_from_slice(&next.data);
+            }
+            (true, false) => self.segments[index - 1].data.extend_from_slice(data),
+            (false, true) => {
+                let next = &mut self.segments[index];
+                let mut joined = data.to_vec();
+                joined.extend_from_slice(&next.data);
+                next.address = address;
+                next.data = joined;
+            }
+            (false, false) => self.segments.insert(
+                index,
+                Segment {
+                    address,
+                    data: data.to_vec(),
+                },
+            ),
+        }
+        Ok(())
+    }
+
+    // Adds all segments of the other image, the entry point of self is kept if both have one
+    pub fn merge(&mut self, other: &MemoryImage) -> Result<(), BootloaderError> {
+        for segment in &other.segments {
+            self.insert(segment.address, &segment.data)?;
+        }
+        self.entry = self.entry.or(other.entry);
+        Ok(())
+    }
+
+    // Fills the gaps between segments with value, resulting in a single segment
+    pub fn fill_gaps(&mut self, value: u8) {
+        let mut segments = std::mem::take(&mut self.segments).into_iter();
+        let Some(mut filled) = segments.next() else {
+            return;
+        };
+        for segment in segments {
+            let gap = (segment.address as u64 - filled.end()) as usize;
+            filled.data.resize(filled.data.len() + gap, value);
+            filled.data.extend_from_slice(&segment.data);
+        }
+        self.segments.push(filled);
+    }
+
+    // Copy in which segments sharing a unit of the given size are joined,
+    // the gap between them is filled with value
+    pub fn joined(&self, unit: usize, value: u8) -> MemoryImage {
+        let unit = unit as u64;
+        let mut segments: Vec<Segment> = Vec::with_capacity(self.segments.len());
+        for segment in &self.segments {
+            match segments.last_mut() {
+                Some(last) if (last.end() - 1) / unit == segment.address as u64 / unit => {
+                    let gap = (segment.address as u64 - last.end()) as usize;
+                    last.data.resize(last.data.len() + gap, value);
+                    last.data.extend_from_slice(&segment.data);
+                }
+                _ => segments.push(segment.clone()),
+            }
+        }
+        MemoryImage {
+            segments,
+            entry: self.entry,
+        }
+    }
+
+    // Removes all data outside of range
+    pub fn crop(&mut self, range: Range<u32>) {
+        let start = range.start as u64;
+        let end = range.end as u64;
+        self.segments.retain_mut(|segment| {
+            if segment.end() <= start || segment.address as u64 >= end {
+                return false;
+            }
+            let from = start.saturating_sub(segment.address as u64) as usize;
+            let to = (end.min(segment.end()) - segment.address as u64) as usize;
+            segment.data.truncate(to);
+            segment.data.drain(..from);
+            segment.address += from as u32;
+            true
+        });
+    }
+
+    // Removes the data outside the flash memory of the device, e.g. the RAM segments
+    // of an ELF file. Without a device only the code region is kept.
+    pub fn crop_to_flash(&mut self, device: Option<&Device>) {
+        let range = match device {
+            Some(device) => device.flash.base..device.flash.end(),
+            None => 0..CODE_REGION_END,
+        };
+        let len = self.len();
+        self.crop(range);
+        if self.len() < len {
+            log::info!("leaving out {} bytes outside of flash", len - self.len());
+        }
+    }
+
+    // Copy of the data inside of range
+    pub fn cropped(&self, range: Range<u32>) -> MemoryImage {
+        let mut image = MemoryImage {
+            segments: self
+                .segments
+                .iter()
+                .filter(|segment| {
+                    (segment.address as u64) < range.end as u64
+                        && segment.end() > range.start as u64
+                })
+                .cloned()
+                .collect(),
+            entry: self.entry,
+        };
+        image.crop(range);
+        image
+    }
+
+    // Fails if two segments share a programming unit of the given size,
+    // the unit would have to be programmed twice
+    pub fn check_granularity(&self, granularity: usize) -> Result<(), BootloaderError> {
+        let granularity = granularity as u64;
+        for pair in self.segments.windows(2) {
+            let last_unit = (pair[0].end() - 1) / granularity;
+            if pair[1].address as u64 / granularity == last_unit {
+                return Err(BootloaderError::PartialProgramUnit {
+                    address: (last_unit * granularity) as u32,
+                    granularity: granularity as usize,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // First address of the image
+    pub fn start(&self) -> Option<u32> {
+        self.segments.first().map(|segment| segment.address)
+    }
+
+    // First address after the image
+    pub fn end(&self) -> Option<u64> {
+        self.segments.last().map(|segment| segment.end())
+    }
+
+    // Number of data bytes in all segments
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    // Address for the Go command. The bootloader expects the vector table there, not the
+    // entry point itself, so the start of the segment containing the entry point is used.
+    pub fn go_address(&self) -> Option<u32> {
+        // the lowest bit marks thumb code
+        let entry = self.entry? & !1;
+        self.segments
+            .iter()
+            .find(|segment| (segment.address as u64..segment.end()).contains(&(entry as u64)))
+            .map(|segment| segment.address)
+    }
+
+    // Checks the initial stack pointer and reset vector at the start of the vector table.
+    // The stack pointer is only checked if the device, and so its RAM, is known.
+    pub fn check_vector_table(&self, device: Option<&Device>) -> Result<(), BootloaderError> {
+        let address = match self.go_address().or(self.start()) {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        let invalid = |reason: String| BootloaderError::InvalidVectorTable { address, reason };
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.address == address)
+            .ok_or_else(|| invalid("no vector table".to_string()))?;
+        if segment.data.len() < 8 {
+            return Err(invalid("image too short".to_string()));
+        }
+        let word = |i: usize| u32::from_le_bytes(segment.data[i..i + 4].try_into().unwrap());
+        let (sp, reset) = (word(0), word(4));
+
+        if let Some(device) = device {
+            // the stack grows down, so the initial value may be the end of the RAM
+            if !device.ram.iter().any(|ram| ram.start < sp && sp <= ram.end) {
+                return Err(invalid(format!(
+                    "initial stack pointer {:#010X} is not in RAM",
+                    sp
+                )));
+            }
+        }
+        if reset & 1 == 0 {
+            return Err(invalid(format!(
+                "reset vector {:#010X} is not thumb code",
+                reset
+            )));
+        }
+        let pc = (reset & !1) as u64;
+        if !self
+            .segments
+            .iter()
+            .any(|segment| (segment.address as u64..segment.end()).contains(&pc))
+        {
+            return Err(invalid(format!(
+                "reset vector {:#010X} is outside of the image",
+                reset
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Reads a firmware file, address is only used for raw binaries
+pub fn load_file<P: AsRef<Path>>(path: P, address: u32) -> Result<MemoryImage, BootloaderError> {
+    read_file(path, address).map(|(_, image)| image)
+}
+
+// Reads a firmware file and returns its format as well
+pub fn read_file<P: AsRef<Path>>(
+    path: P,
+    address: u32,
+) -> Result<(FileFormat, MemoryImage), BootloaderError> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+    let format = match FileFormat::from_path(path) {
+        // executables often have no extension
+        FileFormat::Binary if data.starts_with(elf::MAGIC) => FileFormat::Elf,
+        format => format,
+    };
+    log::debug!("reading {} as {}", path.display(), format);
+    parse(format, data, address).map(|image| (format, image))
+}
+
+pub fn parse(
+    format: FileFormat,
+    data: Vec<u8>,
+    address: u32,
+) -> Result<MemoryImage, BootloaderError> {
+    match format {
+        FileFormat::Binary => Ok(MemoryImage::from_binary(address, data)),
+        FileFormat::IntelHex => ihex::parse(&text_file(format, data)?),
+        FileFormat::SRecord => srec::parse(&text_file(format, data)?),
+        FileFormat::Elf => elf::parse(&data),
+        FileFormat::DfuSe => dfuse::parse(&data),
+    }
+}
+
+fn text_file(format: FileFormat, data: Vec<u8>) -> Result<String, BootloaderError> {
+    String::from_utf8(data).map_err(|_| BootloaderError::InvalidFile {
+        format,
+        reason: "not a text file".to_string(),
+    })
+}
+
+// Decodes pairs of hex digits as used by the text formats
+pub(crate) fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
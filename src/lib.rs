@@ -1,157 +1,201 @@
+mod bootloader;
+pub mod crc;
+pub mod decode;
+pub mod device;
+pub mod dfuse;
+pub mod elf;
+mod error;
 mod flasher;
 pub mod helper;
-
-pub use flasher::{FlashConfig, Flasher};
-
+pub mod ihex;
+pub mod image;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod plan;
+pub mod protocol;
+pub mod srec;
+pub mod trace;
+
+pub use bootloader::{Bootloader, EraseStrategy, EraseTarget, SectorDiff};
+pub use device::ExpectedChip;
+pub use error::{BootloaderError, Stage};
+pub use flasher::{plan_flash, plan_write, prepare_flash, FlashConfig, Flasher, PreparedFlash};
+pub use image::{FileFormat, MemoryImage, Segment};
+pub use plan::FlashPlan;
+use protocol::{Event, Exchange, Request, Response};
 // https://www.st.com/resource/en/application_note/an3155-usart-protocol-used-in-the-stm32-bootloader-stmicroelectronics.pdf
+use std::borrow::Cow;
+use std::fmt;
 use std::io::prelude::*;
-use std::io::Error;
-use std::io::ErrorKind;
-
-const GET_COMMAND: [u8; 2] = [0x00, 0xFF];
-const GET_VERSION_COMMAND: [u8; 2] = [0x01, 0xFE];
-const GET_ID_COMMAND: [u8; 2] = [0x02, 0xFD];
-const READ_MEMORY_COMMAND: [u8; 2] = [0x11, 0xEE];
-const GO_COMMAND: [u8; 2] = [0x21, 0xDE];
-const WRITE_MEMORY_COMMAND: [u8; 2] = [0x31, 0xCE];
-const ERASE_MEMORY_COMMAND: [u8; 2] = [0x43, 0xBC];
-const EXTENDED_ERASE_MEMORY_COMMAND: [u8; 2] = [0x44, 0xBB];
-
-const ACK: u8 = 0x79;
-#[allow(dead_code)]
-const NACK: u8 = 0x1F;
-
-const HELLO_BYTE: u8 = 0x7F;
-
-pub fn hello<T: Read + Write>(port: &mut T) -> Result<(), Error> {
-    // Send "Hello" byte
-    port.write(&[HELLO_BYTE])?;
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Hello byte",
-        ));
-    }
-    log::debug!("got ack after hello byte");
-
-    Ok(())
+use std::thread::sleep;
+use std::time::Duration;
+
+// Bootloader commands as defined in AN3155
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    Hello,
+    Get,
+    GetVersion,
+    GetId,
+    ReadMemory,
+    Go,
+    WriteMemory,
+    Erase,
+    ExtendedErase,
+    Special,
+    ExtendedSpecial,
+    WriteProtect,
+    WriteUnprotect,
+    ReadoutProtect,
+    ReadoutUnprotect,
+    GetChecksum,
 }
 
-// Returns the version and supported commands
-pub fn get<T: Read + Write>(port: &mut T) -> Result<(u8, Vec<u8>), Error> {
-    // Send "Get" command
-    port.write(&GET_COMMAND)?;
-
-    log::trace!("GET_COMMAND: {:?}", GET_COMMAND);
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Get command: ".to_string() + &response[0].to_string(),
-        ));
+impl Command {
+    pub fn opcode(self) -> u8 {
+        match self {
+            Command::Hello => 0x7F,
+            Command::Get => 0x00,
+            Command::GetVersion => 0x01,
+            Command::GetId => 0x02,
+            Command::ReadMemory => 0x11,
+            Command::Go => 0x21,
+            Command::WriteMemory => 0x31,
+            Command::Erase => 0x43,
+            Command::ExtendedErase => 0x44,
+            Command::Special => 0x50,
+            Command::ExtendedSpecial => 0x51,
+            Command::WriteProtect => 0x63,
+            Command::WriteUnprotect => 0x73,
+            Command::ReadoutProtect => 0x82,
+            Command::ReadoutUnprotect => 0x92,
+            Command::GetChecksum => 0xA1,
+        }
     }
 
-    log::trace!("read");
-
-    // Read number of bytes to follow
-    port.read(&mut response)?;
-    let num_bytes = response[0] as usize + 1;
-
-    log::trace!("num_bytes: {}", num_bytes);
-
-    // Read data bytes
-    let mut data = vec![0; num_bytes];
-    port.read_exact(&mut data)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after data: ".to_string() + &response[0].to_string(),
-        ));
+    pub fn from_opcode(opcode: u8) -> Option<Command> {
+        let command = match opcode {
+            0x7F => Command::Hello,
+            0x00 => Command::Get,
+            0x01 => Command::GetVersion,
+            0x02 => Command::GetId,
+            0x11 => Command::ReadMemory,
+            0x21 => Command::Go,
+            0x31 => Command::WriteMemory,
+            0x43 => Command::Erase,
+            0x44 => Command::ExtendedErase,
+            0x50 => Command::Special,
+            0x51 => Command::ExtendedSpecial,
+            0x63 => Command::WriteProtect,
+            0x73 => Command::WriteUnprotect,
+            0x82 => Command::ReadoutProtect,
+            0x92 => Command::ReadoutUnprotect,
+            0xA1 => Command::GetChecksum,
+            _ => return None,
+        };
+        Some(command)
     }
-
-    let version = data[0];
-    let supported_commands = data[1..].to_vec();
-    Ok((version, supported_commands))
 }
 
-pub fn get_version<T: Read + Write>(port: &mut T) -> Result<u8, Error> {
-    // Send "Get Version" command
-    port.write(&GET_VERSION_COMMAND)?;
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Get Version command: ".to_string()
-                + &response[0].to_string(),
-        ));
-    }
-
-    // Read version and supported commands
-    let mut version_and_commands = [0; 3];
-    port.read(&mut version_and_commands)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after version and commands: ".to_string()
-                + &response[0].to_string(),
-        ));
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Command::Hello => "Hello",
+            Command::Get => "Get",
+            Command::GetVersion => "Get Version",
+            Command::GetId => "Get ID",
+            Command::ReadMemory => "Read Memory",
+            Command::Go => "Go",
+            Command::WriteMemory => "Write Memory",
+            Command::Erase => "Erase Memory",
+            Command::ExtendedErase => "Extended Erase Memory",
+            Command::Special => "Special",
+            Command::ExtendedSpecial => "Extended Special",
+            Command::WriteProtect => "Write Protect",
+            Command::WriteUnprotect => "Write Unprotect",
+            Command::ReadoutProtect => "Readout Protect",
+            Command::ReadoutUnprotect => "Readout Unprotect",
+            Command::GetChecksum => "Get Checksum",
+        };
+        f.write_str(name)
     }
+}
 
-    let version = version_and_commands[0];
-    let _backwards_compatibility = &version_and_commands[1..];
+// Write Memory needs the start address and the length to be a multiple of 4
+pub(crate) const WRITE_ALIGNMENT: usize = 4;
 
-    Ok(version)
+// Runs a request over a blocking transport, the command functions are built on it
+pub fn execute<T: Read + Write>(
+    port: &mut T,
+    request: Request,
+) -> Result<Response, BootloaderError> {
+    let mut exchange = Exchange::new(request)?;
+    let mut buf = [0; 256];
+    loop {
+        match exchange.poll() {
+            Event::Send(data) => port.write_all(&data)?,
+            Event::Receive(len) => {
+                let len = std::cmp::min(len, buf.len());
+                port.read_exact(&mut buf[..len]).map_err(|e| {
+                    BootloaderError::from_io(
+                        e,
+                        exchange.command(),
+                        exchange.stage(),
+                        exchange.address(),
+                    )
+                })?;
+                exchange.receive(&buf[..len])?;
+            }
+            Event::Done(response) => return Ok(response),
+        }
+    }
 }
 
-pub fn get_id<T: Read + Write>(port: &mut T) -> Result<u16, Error> {
-    // Send "Get ID" command
-    port.write(&GET_ID_COMMAND)?;
+pub fn hello<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    execute(port, Request::Hello)?;
+    log::debug!("got ack after hello byte");
+    Ok(())
+}
 
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Get ID command",
-        ));
+// Re-synchronises with the bootloader after it performed a system reset,
+// e.g. after the option bytes have been changed
+pub fn reconnect<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    let mut last_err = BootloaderError::Timeout {
+        command: Command::Hello,
+        stage: Stage::Command,
+        address: None,
+    };
+    for _ in 0..10 {
+        // give the bootloader some time to come back up
+        sleep(Duration::from_millis(100));
+        if let Err(e) = hello(port) {
+            last_err = e;
+        } else {
+            return Ok(());
+        }
     }
+    Err(last_err)
+}
 
-    // Read number of bytes to follow
-    port.read(&mut response)?;
-    let _num_bytes = response[0] as usize;
-
-    // Read product ID
-    let mut id_bytes = [0; 2];
-    port.read_exact(&mut id_bytes)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after product ID: ".to_string() + &response[0].to_string(),
-        ));
-    }
+// Returns the version and supported commands
+pub fn get<T: Read + Write>(port: &mut T) -> Result<(u8, Vec<u8>), BootloaderError> {
+    let Response::Get { version, commands } = execute(port, Request::Get)? else {
+        unreachable!()
+    };
+    Ok((version, commands))
+}
 
-    let id = u16::from_be_bytes(id_bytes);
+pub fn get_version<T: Read + Write>(port: &mut T) -> Result<u8, BootloaderError> {
+    let Response::Version(version) = execute(port, Request::GetVersion)? else {
+        unreachable!()
+    };
+    Ok(version)
+}
 
+pub fn get_id<T: Read + Write>(port: &mut T) -> Result<u16, BootloaderError> {
+    let Response::Id(id) = execute(port, Request::GetId)? else {
+        unreachable!()
+    };
     Ok(id)
 }
 
@@ -159,59 +203,15 @@ pub fn read_memory<T: Read + Write>(
     port: &mut T,
     address: u32,
     dst_data: &mut [u8],
-) -> Result<(), Error> {
-    if dst_data.len() > 256 || dst_data.len() == 0 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Buffer size must be less than or equal to 256",
-        ));
-    }
-    let num_bytes = (dst_data.len() - 1) as u8;
-    // Send "Read Memory" command
-    port.write(&READ_MEMORY_COMMAND)?;
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Read Memory command",
-        ));
-    }
-
-    // Send address
-    let mut buf = Vec::with_capacity(5);
-    buf.extend_from_slice(&address.to_be_bytes());
-    let checksum = buf.iter().fold(0, |acc, &x| acc ^ x);
-    buf.push(checksum);
-    // log::trace!("address: {:?}", buf);
-    port.write(&buf)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after address",
-        ));
-    }
-
-    // Send number of bytes to read and checksum
-    let checksum = num_bytes ^ 0xFF;
-    port.write(&[num_bytes, checksum])?;
-
-    // Wait for ACK and read data
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after number of bytes",
-        ));
-    }
-
-    port.read(dst_data)?;
-
+) -> Result<(), BootloaderError> {
+    let request = Request::ReadMemory {
+        address,
+        len: dst_data.len(),
+    };
+    let Response::Data(data) = execute(port, request)? else {
+        unreachable!()
+    };
+    dst_data.copy_from_slice(&data);
     Ok(())
 }
 
@@ -219,7 +219,7 @@ pub fn read_memory_vec<T: Read + Write>(
     port: &mut T,
     address: u32,
     num_bytes: usize,
-) -> Result<Vec<u8>, Error> {
+) -> Result<Vec<u8>, BootloaderError> {
     let mut data = vec![0; num_bytes];
     let mut offset = 0;
     while offset < num_bytes {
@@ -234,223 +234,258 @@ pub fn read_memory_vec<T: Read + Write>(
     Ok(data)
 }
 
-pub fn go<T: Read + Write>(port: &mut T, address: u32) -> Result<(), Error> {
-    // Send "Go" command
-    port.write(&GO_COMMAND)?;
+// Returns the CRC of the memory area computed on the device (bootloader v3.3+).
+// Address and length must be a multiple of 4, see crc::stm32_crc32 for the host side.
+pub fn get_checksum<T: Read + Write>(
+    port: &mut T,
+    address: u32,
+    len: u32,
+) -> Result<u32, BootloaderError> {
+    let Response::Checksum(crc) = execute(port, Request::GetChecksum { address, len })? else {
+        unreachable!()
+    };
+    Ok(crc)
+}
 
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Go command",
-        ));
-    }
+pub fn go<T: Read + Write>(port: &mut T, address: u32) -> Result<(), BootloaderError> {
+    execute(port, Request::Go { address })?;
+    Ok(())
+}
 
-    // Send address
-    let mut buf = Vec::with_capacity(5);
-    buf.extend_from_slice(&address.to_be_bytes());
-    let checksum = buf.iter().fold(0, |acc, &x| acc ^ x);
-    buf.push(checksum);
-    // log::trace!("address: {:?}", buf);
-    port.write(&buf)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after address",
-        ));
-    }
+// Enables readout protection (RDP level 1).
+// The device performs a system reset afterwards, this reconnects to the bootloader.
+pub fn readout_protect<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    execute(port, Request::ReadoutProtect)?;
+    log::debug!("readout protection set, waiting for system reset");
+    reconnect(port)
+}
 
-    Ok(())
+// Disables readout protection (back to RDP level 0).
+// WARNING: this triggers a mass erase of the whole flash memory.
+// The device performs a system reset afterwards, this reconnects to the bootloader.
+pub fn readout_unprotect<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    log::debug!("wait for mass erase complete");
+    execute(port, Request::ReadoutUnprotect)?;
+    log::debug!("readout protection removed, waiting for system reset");
+    reconnect(port)
 }
 
 pub fn write_memory_block<T: Read + Write>(
     port: &mut T,
     address: u32,
     data: &[u8],
-) -> Result<(), Error> {
-    // Send "Write Memory" command
-    port.write(&WRITE_MEMORY_COMMAND)?;
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Write Memory command",
-        ));
-    }
-
-    // Send address
-    let mut buf = Vec::with_capacity(5);
-    buf.extend_from_slice(&address.to_be_bytes());
-    let checksum = buf.iter().fold(0, |acc, &x| acc ^ x);
-    buf.push(checksum);
-    // log::trace!("address: {:?}", buf);
-    port.write(&buf)?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after address",
-        ));
-    }
+) -> Result<(), BootloaderError> {
+    let request = Request::WriteMemory {
+        address,
+        data: data.to_vec(),
+    };
+    execute(port, request)?;
+    Ok(())
+}
 
-    // Send number of bytes and data
-    let length = (data.len() - 1) as u8; // Subtract 1 as per protocol
-    let checksum = data.iter().fold(length, |acc, &x| acc ^ x);
-    port.write(&[length])?;
-    port.write(data)?;
-    port.write(&[checksum])?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after data",
-        ));
-    }
+// Which blocks write and verify leave out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SkipPolicy {
+    // Write and verify every block
+    #[default]
+    Never,
+    // Leave out blocks consisting only of the erased value,
+    // the memory has to be erased before writing
+    Erased,
+}
 
-    Ok(())
+// What to do if the vector table of an image does not fit the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorCheck {
+    #[default]
+    Off,
+    Warn,
+    Abort,
 }
 
-pub fn write_memory<T: Read + Write>(port: &mut T, address: u32, data: &[u8]) -> Result<(), Error> {
-    let mut offset = 0;
-    while offset < data.len() {
-        let block_size = std::cmp::min(data.len() - offset, 256);
-        if data[offset..offset + block_size].iter().all(|&x| x == 0x00) {
-            log::trace!("skipping empty block at {:#x}", address + offset as u32);
-            offset += block_size;
-            continue;
+impl VectorCheck {
+    pub fn check(
+        self,
+        image: &MemoryImage,
+        device: Option<&device::Device>,
+    ) -> Result<(), BootloaderError> {
+        if self == VectorCheck::Off {
+            return Ok(());
+        }
+        match image.check_vector_table(device) {
+            Err(e) if self == VectorCheck::Warn => {
+                log::warn!("{}", e);
+                Ok(())
+            }
+            res => res,
         }
-        log::trace!("write to block: {:#x}", address + offset as u32);
-        write_memory_block(
-            port,
-            address + offset as u32,
-            &data[offset..offset + block_size],
-        )?;
-        offset += block_size;
     }
-    Ok(())
 }
 
-pub fn erase_memory<T: Read + Write>(port: &mut T, sectors: &[u8]) -> Result<(), Error> {
-    // Send "Erase Memory" command
-    port.write(&ERASE_MEMORY_COMMAND)?;
-
-    // Wait for ACK
-    let mut response = [0; 1];
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Erase Memory command",
-        ));
-    }
+impl std::str::FromStr for VectorCheck {
+    type Err = String;
 
-    // Send number of sectors and sector numbers
-    let length = (sectors.len() - 1) as u8; // Subtract 1 as per protocol
-    let checksum = sectors.iter().fold(length, |acc, &x| acc ^ x);
-    port.write(&[length])?;
-    port.write(sectors)?;
-    port.write(&[checksum])?;
-
-    // Wait for ACK
-    port.read(&mut response)?;
-    if response[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after sectors",
-        ));
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(VectorCheck::Off),
+            "warn" => Ok(VectorCheck::Warn),
+            "abort" => Ok(VectorCheck::Abort),
+            _ => Err(format!("Unknown vector table check: {}", s)),
+        }
     }
-
-    Ok(())
 }
 
-pub fn erase_memory_global<T: Read + Write>(port: &mut T) -> Result<(), Error> {
-    // Send the "Global Erase" command
-    port.write(&ERASE_MEMORY_COMMAND)?;
-
-    // Wait for ACK
-    let mut ack: [u8; 1] = [0];
-    port.read(&mut ack)?;
+// How data is written to the device. Verification uses the same options,
+// so both agree on the blocks which are left out and the padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    // Value erased memory reads as
+    pub erased_value: u8,
+    pub skip: SkipPolicy,
+    // Size of the unit the flash memory is programmed in, writes are aligned and padded to it
+    pub granularity: usize,
+}
 
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Erase Memory command",
-        ));
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            erased_value: 0xFF,
+            skip: SkipPolicy::Never,
+            granularity: WRITE_ALIGNMENT,
+        }
     }
+}
 
-    // Send the number of pages to erase. 0xFF00 means global erase.
-    const GLOBAL_ERASE_PAGES: [u8; 2] = [0xFF, 0x00];
-    port.write(&GLOBAL_ERASE_PAGES)?;
+impl WriteOptions {
+    // Options with the erased value and the programming unit of the device flash memory
+    pub fn for_device(device: &device::Device) -> Self {
+        WriteOptions {
+            erased_value: device.erased_value(),
+            granularity: device.write_granularity(),
+            ..Default::default()
+        }
+    }
 
-    // Wait for ACK
-    port.read(&mut ack)?;
+    // Raises the granularity to the programming unit of the device
+    pub fn apply_device(&mut self, device: &device::Device) {
+        self.granularity = self.granularity.max(device.write_granularity());
+    }
 
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after erase sectors",
-        ));
+    fn alignment(&self) -> usize {
+        self.granularity.max(WRITE_ALIGNMENT)
     }
 
-    Ok(())
+    pub fn skips(&self, block: &[u8]) -> bool {
+        match self.skip {
+            SkipPolicy::Never => false,
+            SkipPolicy::Erased => block.iter().all(|&x| x == self.erased_value),
+        }
+    }
 }
 
-pub fn extended_erase<T: Read + Write>(port: &mut T, pages: &[u16]) -> Result<(), Error> {
-    // Command code for "Extended Erase" is 0x44
-    port.write(&EXTENDED_ERASE_MEMORY_COMMAND)?;
+// Start address, number of bytes added in front and length of a write after aligning it
+pub(crate) fn aligned_range(
+    address: u32,
+    len: usize,
+    options: WriteOptions,
+) -> (u32, usize, usize) {
+    let alignment = options.alignment();
+    let head = address as usize % alignment;
+    let len = (head + len).next_multiple_of(alignment);
+    (address - head as u32, head, len)
+}
 
-    // Wait for ACK
-    let mut ack: [u8; 1] = [0];
-    port.read(&mut ack)?;
+// Splits aligned data into the blocks sent with Write Memory, the flag is set for
+// blocks which are left out according to the skip policy
+pub(crate) fn write_blocks(
+    address: u32,
+    data: &[u8],
+    options: WriteOptions,
+) -> impl Iterator<Item = (u32, &[u8], bool)> {
+    data.chunks(256)
+        .enumerate()
+        .map(move |(i, block)| (address + (i * 256) as u32, block, options.skips(block)))
+}
 
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Extended Erase Memory command",
-        ));
+// Returns the data as write_memory writes it. An unaligned start is extended to the
+// previous programming unit boundary with the current memory contents (read-modify-write),
+// the end is padded with the erased value.
+pub(crate) fn align_write<'a, T: Read + Write>(
+    port: &mut T,
+    address: u32,
+    data: &'a [u8],
+    options: WriteOptions,
+) -> Result<(u32, Cow<'a, [u8]>), BootloaderError> {
+    let (start, head, len) = aligned_range(address, data.len(), options);
+    if head == 0 && len == data.len() {
+        return Ok((address, Cow::Borrowed(data)));
     }
-
-    let mut bytes_to_send = Vec::<u8>::new();
-    // Send the number of pages to erase
-    bytes_to_send.extend_from_slice(&(pages.len() as u16).to_be_bytes());
-
-    // Send the page numbers to erase
-    for page in pages {
-        bytes_to_send.extend_from_slice(&page.to_be_bytes());
+    let mut aligned = vec![options.erased_value; head];
+    if head > 0 {
+        log::trace!("read-modify-write at {:#010X}", start);
+        read_memory(port, start, &mut aligned)?;
     }
-    // Calculate the checksum
-    let checksum = bytes_to_send.iter().fold(0, |acc, &x| acc ^ x);
-    bytes_to_send.push(checksum);
+    aligned.extend_from_slice(data);
+    aligned.resize(len, options.erased_value);
+    Ok((start, Cow::Owned(aligned)))
+}
 
-    port.write(&bytes_to_send)?;
+// Writes data of any length to any address, see align_write
+pub fn write_memory<T: Read + Write>(
+    port: &mut T,
+    address: u32,
+    data: &[u8],
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let head = address as usize % options.alignment();
+    let (address, data) = align_write(port, address, data, options)?;
+    // A programming unit with ECC can only be written once after erasing
+    if options.granularity > WRITE_ALIGNMENT
+        && data[..head].iter().any(|&x| x != options.erased_value)
+    {
+        return Err(BootloaderError::PartialProgramUnit {
+            address,
+            granularity: options.granularity,
+        });
+    }
+    for (address, block, skipped) in write_blocks(address, &data, options) {
+        if skipped {
+            log::trace!("skipping empty block at {:#x}", address);
+            continue;
+        }
+        log::trace!("write to block: {:#x}", address);
+        write_memory_block(port, address, block)?;
+    }
+    Ok(())
+}
 
-    // Wait for ACK
-    log::debug!("wait for erase complete");
-    port.read(&mut ack)?;
+pub fn erase_memory<T: Read + Write>(port: &mut T, sectors: &[u8]) -> Result<(), BootloaderError> {
+    let request = Request::Erase {
+        sectors: sectors.to_vec(),
+    };
+    execute(port, request)?;
+    Ok(())
+}
 
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after erase sectors",
-        ));
-    }
+pub fn erase_memory_global<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    execute(port, Request::GlobalErase)?;
+    Ok(())
+}
 
+pub fn extended_erase<T: Read + Write>(port: &mut T, pages: &[u16]) -> Result<(), BootloaderError> {
+    let request = Request::ExtendedErase {
+        pages: pages.to_vec(),
+    };
+    log::debug!("wait for erase complete");
+    execute(port, request)?;
     Ok(())
 }
 
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SpecialEraseType {
     MassErase = 0xFFFF,
     Bank1Erase = 0xFFFE,
@@ -460,76 +495,149 @@ pub enum SpecialEraseType {
 pub fn extended_erase_special<T: Read + Write>(
     port: &mut T,
     cmd: SpecialEraseType,
-) -> Result<(), Error> {
-    // Send the "Extended Erase" command
-    port.write(&EXTENDED_ERASE_MEMORY_COMMAND)?;
-
-    // Wait for ACK
-    let mut ack: [u8; 1] = [0];
-    port.read(&mut ack)?;
-
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after Extended Erase Memory command",
-        ));
-    }
+) -> Result<(), BootloaderError> {
+    log::debug!("wait for erase complete");
+    execute(port, Request::SpecialErase(cmd))?;
+    Ok(())
+}
 
-    let mut bytes_to_send = Vec::<u8>::new();
-    // Send the number of pages to erase
-    bytes_to_send.extend_from_slice(&(cmd as u16).to_be_bytes());
+// Enables write protection for the given sectors.
+// The device performs a system reset afterwards, this reconnects to the bootloader.
+pub fn write_protect<T: Read + Write>(port: &mut T, sectors: &[u8]) -> Result<(), BootloaderError> {
+    let request = Request::WriteProtect {
+        sectors: sectors.to_vec(),
+    };
+    execute(port, request)?;
+    log::debug!("write protection set, waiting for system reset");
+    reconnect(port)
+}
 
-    // Calculate the checksum
-    let checksum = bytes_to_send.iter().fold(0, |acc, &x| acc ^ x);
-    bytes_to_send.push(checksum);
+// Disables write protection for the whole flash memory.
+// The device performs a system reset afterwards, this reconnects to the bootloader.
+pub fn write_unprotect<T: Read + Write>(port: &mut T) -> Result<(), BootloaderError> {
+    execute(port, Request::WriteUnprotect)?;
+    log::debug!("write protection removed, waiting for system reset");
+    reconnect(port)
+}
 
-    port.write(&bytes_to_send)?;
+// Response of a Special or Extended Special command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpecialResponse {
+    pub data: Vec<u8>,
+    pub status: Vec<u8>,
+}
 
-    // Wait for ACK
-    log::debug!("wait for erase complete");
-    port.read(&mut ack)?;
+// Sends a family specific Special command (bootloader v3.3+).
+// The request data is limited to 128 bytes.
+pub fn special_command<T: Read + Write>(
+    port: &mut T,
+    opcode: u16,
+    data: &[u8],
+) -> Result<SpecialResponse, BootloaderError> {
+    let request = Request::Special {
+        opcode,
+        data: data.to_vec(),
+    };
+    let Response::Special(response) = execute(port, request)? else {
+        unreachable!()
+    };
+    Ok(response)
+}
 
-    if ack[0] != ACK {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Did not receive ACK after erase sectors",
-        ));
-    }
+// Sends a family specific Extended Special command (bootloader v3.3+).
+// The first data packet is limited to 128 bytes, the second one to 1024 bytes.
+// The device only answers with status bytes.
+pub fn extended_special_command<T: Read + Write>(
+    port: &mut T,
+    opcode: u16,
+    data: &[u8],
+    data2: &[u8],
+) -> Result<SpecialResponse, BootloaderError> {
+    let request = Request::ExtendedSpecial {
+        opcode,
+        data: data.to_vec(),
+        data2: data2.to_vec(),
+    };
+    let Response::Special(response) = execute(port, request)? else {
+        unreachable!()
+    };
+    Ok(response)
+}
 
+// Writes all segments of the image
+pub fn write_image<T: Read + Write>(
+    port: &mut T,
+    image: &MemoryImage,
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    if options.granularity > WRITE_ALIGNMENT {
+        image.check_granularity(options.granularity)?;
+    }
+    // a word shared by two segments is written once
+    let image = image.joined(WRITE_ALIGNMENT, options.erased_value);
+    for segment in image.segments() {
+        log::debug!(
+            "writing {} bytes to {:#010X}",
+            segment.data.len(),
+            segment.address
+        );
+        write_memory(port, segment.address, &segment.data, options)?;
+    }
     Ok(())
 }
 
-pub fn flash_file<T: Read + Write>(port: &mut T, file: &str, address: u32) -> Result<(), Error> {
-    let mut file = std::fs::File::open(file)?;
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
-    const PAGE_SIZE: u32 = 0x800;
-    let _num_pages = (data.len() as f32 / PAGE_SIZE as f32).ceil() as u8;
-    let _page_offset = (address % PAGE_SIZE) as u8;
-    // TODO: always erase block 0 and 1 ???
-    // extended_erase(port, &[0, 1])?;
-    // if let Err(e) = extended_erase_special(port, SpecialEraseType::MassErase) {
-    //     log::warn!("Failed to erase memory: {:?}", e);
-    // }
-    // erase_memory(port, &[0,1])?;
-    // erase_memory_global(port)?;
-
-    write_memory(port, address, &data)?;
+// Writes a firmware file, address is only used for raw binaries
+pub fn flash_file<T: Read + Write>(
+    port: &mut T,
+    file: &str,
+    address: u32,
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    let image = load_flash_file(port, file, address)?;
+    write_image(port, &image, options)
+}
 
-    Ok(())
+// Reads a firmware file, only the flash part of ELF files is kept
+fn load_flash_file<T: Read + Write>(
+    port: &mut T,
+    file: &str,
+    address: u32,
+) -> Result<MemoryImage, BootloaderError> {
+    let (format, mut image) = image::read_file(file, address)?;
+    if format == image::FileFormat::Elf {
+        image.crop_to_flash(get_id(port).ok().and_then(device::lookup));
+    }
+    Ok(image)
 }
 
+// Verifies the memory using Get Checksum if the bootloader supports it,
+// otherwise the memory is read back. Only data is compared, not the padding added by write_memory.
+// Queries the supported commands, use Bootloader::verify_memory for repeated calls.
 pub fn verify_memory<T: Read + Write>(
     port: &mut T,
     address: u32,
     data: &[u8],
-) -> Result<(), Error> {
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    Bootloader::attach(port)?.verify_memory(address, data, options)
+}
+
+// Verifies the memory by reading back every block
+pub fn verify_memory_readback<T: Read + Write>(
+    port: &mut T,
+    address: u32,
+    data: &[u8],
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
     for (i, chunk) in data.chunks(256).enumerate() {
         let offset = i * 256;
         let address = address + offset as u32;
 
-        if chunk.iter().all(|&x| x == 0x00) {
-            log::trace!("skipping empty block at {:#010X}", address as u32);
+        if options.skips(chunk) {
+            log::trace!("skipping empty block at {:#010X}", address);
             continue;
         }
         log::trace!("verify block: {:#x}", address);
@@ -548,7 +656,98 @@ pub fn verify_memory<T: Read + Write>(
     Ok(())
 }
 
-fn validate_block<T: Read + Write>(port: &mut T, chunk: &[u8], address: u32) -> Result<(), Error> {
+// Maximum size of the memory area covered by one Get Checksum command.
+// On a mismatch only this much has to be read back to locate the difference.
+pub(crate) const CHECKSUM_CHUNK_SIZE: usize = 0x10000;
+
+// Verifies the memory by comparing the CRC computed on the device with the CRC
+// of the data. Blocks are skipped the same way write_memory does.
+pub fn verify_memory_checksum<T: Read + Write>(
+    port: &mut T,
+    address: u32,
+    data: &[u8],
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    if !address.is_multiple_of(4) {
+        // read back the bytes up to the next word, Get Checksum needs an aligned address
+        let head = std::cmp::min(address.next_multiple_of(4) - address, data.len() as u32);
+        log::debug!(
+            "unaligned address {:#010X}, reading back {} bytes",
+            address,
+            head
+        );
+        verify_memory_readback(port, address, &data[..head as usize], options)?;
+        return verify_memory_checksum(port, address + head, &data[head as usize..], options);
+    }
+
+    // Collect the runs of consecutive non empty blocks
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, chunk) in data.chunks(256).enumerate() {
+        let offset = i * 256;
+        if options.skips(chunk) {
+            log::trace!("skipping empty block at {:#010X}", address + offset as u32);
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, end)) if *end == offset => *end = offset + chunk.len(),
+            _ => runs.push((offset, offset + chunk.len())),
+        }
+    }
+
+    for (start, end) in runs {
+        let mut offset = start;
+        while offset < end {
+            let len = std::cmp::min(end - offset, CHECKSUM_CHUNK_SIZE);
+            let aligned_len = len - len % 4;
+            let chunk_address = address + offset as u32;
+            let chunk = &data[offset..offset + len];
+
+            if aligned_len > 0 {
+                log::trace!(
+                    "verify checksum: {:#x} ({} bytes)",
+                    chunk_address,
+                    aligned_len
+                );
+                let device_crc = get_checksum(port, chunk_address, aligned_len as u32)?;
+                let expected_crc = crc::stm32_crc32(&chunk[..aligned_len]);
+                if device_crc != expected_crc {
+                    log::debug!(
+                        "Checksum mismatch at {:#010X}: expected {:#010x}, got {:#010x}",
+                        chunk_address,
+                        expected_crc,
+                        device_crc
+                    );
+                    // locate the mismatch
+                    verify_memory_readback(port, chunk_address, &chunk[..aligned_len], options)?;
+                    // the data is fine, so the checksum of the device is wrong
+                    return Err(BootloaderError::ChecksumMismatch {
+                        address: chunk_address,
+                        len: aligned_len,
+                        expected: expected_crc,
+                        actual: device_crc,
+                    });
+                }
+            }
+            if aligned_len < len {
+                // tail which is not a multiple of 4
+                verify_memory_readback(
+                    port,
+                    chunk_address + aligned_len as u32,
+                    &chunk[aligned_len..],
+                    options,
+                )?;
+            }
+            offset += len;
+        }
+    }
+    Ok(())
+}
+
+fn validate_block<T: Read + Write>(
+    port: &mut T,
+    chunk: &[u8],
+    address: u32,
+) -> Result<(), BootloaderError> {
     let mut device_data_buf = [0; 256];
     let mut device_data_vec;
     let device_data = if chunk.len() == 256 {
@@ -559,31 +758,47 @@ fn validate_block<T: Read + Write>(port: &mut T, chunk: &[u8], address: u32) ->
         read_memory(port, address, &mut device_data_vec)?;
         &device_data_vec[..]
     };
-    Ok(
-        for (i, (a, b)) in device_data.iter().zip(chunk.iter()).enumerate() {
-            if a != b {
-                log::debug!("device: {:?}", &device_data[i.saturating_sub(12)..i + 12]);
-                log::debug!("data  : {:?}", &chunk[i.saturating_sub(12)..i + 12]);
-
-                log::debug!(
-                    "Mismatch at offset {:#010X}: expected {:#02x}, got {:#02x}",
-                    address,
-                    b,
-                    a
-                );
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Mismatch at {:#010X}", address + i as u32),
-                ));
-            }
-        },
-    )
+    for (i, (a, b)) in device_data.iter().zip(chunk.iter()).enumerate() {
+        if a != b {
+            let context = i.saturating_sub(12)..std::cmp::min(i + 12, chunk.len());
+            log::debug!("device: {:?}", &device_data[context.clone()]);
+            log::debug!("data  : {:?}", &chunk[context]);
+
+            log::debug!(
+                "Mismatch at offset {:#010X}: expected {:#02x}, got {:#02x}",
+                address,
+                b,
+                a
+            );
+            return Err(BootloaderError::Mismatch {
+                address: address + i as u32,
+                expected: *b,
+                actual: *a,
+            });
+        }
+    }
+    Ok(())
 }
 
-pub fn verify_file<T: Read + Write>(port: &mut T, file: &str, address: u32) -> Result<(), Error> {
-    let mut file = std::fs::File::open(file)?;
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
+pub fn verify_image<T: Read + Write>(
+    port: &mut T,
+    image: &MemoryImage,
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    let mut bootloader = Bootloader::attach(port)?;
+    for segment in image.segments() {
+        bootloader.verify_memory(segment.address, &segment.data, options)?;
+    }
+    Ok(())
+}
 
-    verify_memory(port, address, &data)
+// Verifies a firmware file, address is only used for raw binaries
+pub fn verify_file<T: Read + Write>(
+    port: &mut T,
+    file: &str,
+    address: u32,
+    options: WriteOptions,
+) -> Result<(), BootloaderError> {
+    let image = load_flash_file(port, file, address)?;
+    verify_image(port, &image, options)
 }
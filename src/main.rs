@@ -1,15 +1,28 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use parse_int::parse;
+use serialport::SerialPort;
 use std::time::Duration;
-use stm32_firmware_loader::helper::{connect_port, toggle_reset, GpioPin};
+use stm32_firmware_loader::helper::{
+    connect_port, open_port, synchronise, toggle_reset, GpioPin, COMMAND_TIMEOUT,
+};
+use stm32_firmware_loader::trace::{Recorder, TraceWriter};
 use stm32_firmware_loader::*;
 
 fn main() {
     env_logger::builder()
-            .parse_filters("info,stm32_firmware_loader=debug")
-            .parse_default_env()
-            .try_init()
-            .expect("Could not init Logging System");
+        .parse_filters("info,stm32_firmware_loader=debug")
+        .parse_default_env()
+        .try_init()
+        .expect("Could not init Logging System");
+    let skip_erased = Arg::with_name("skip-erased")
+        .long("skip-erased")
+        .help("Skip blocks which only contain the erased value of the device");
+    let fill_gaps = Arg::with_name("fill-gaps")
+        .long("fill-gaps")
+        .help("Fill the gaps between the segments of the file with the erased value of the device");
+    let dry_run = Arg::with_name("dry-run").long("dry-run").help(
+        "Print what would be erased and written, only Hello, Get and Get ID are sent to the device",
+    );
     let matches = App::new("STM32 Bootloader Utility")
         .version("1.0")
         .author("KBST GmbH <info@kbst-gmbh.de>")
@@ -50,6 +63,13 @@ fn main() {
                 .takes_value(true)
                 .default_value("8"),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("Records the bytes exchanged with the bootloader to FILE")
+                .takes_value(true),
+        )
         .subcommand(SubCommand::with_name("get"))
         .subcommand(SubCommand::with_name("get_version"))
         .subcommand(SubCommand::with_name("get_id"))
@@ -59,7 +79,16 @@ fn main() {
                 .arg(Arg::with_name("size").required(true)),
         )
         .subcommand(
-            SubCommand::with_name("go").arg(Arg::with_name("address").default_value("0x08000000")),
+            SubCommand::with_name("get_checksum")
+                .arg(Arg::with_name("address").required(true))
+                .arg(Arg::with_name("size").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("go").arg(
+                Arg::with_name("address")
+                    .default_value("0x08000000")
+                    .help("Start address of raw binary files"),
+            ),
         )
         .subcommand(
             SubCommand::with_name("write_memory")
@@ -73,28 +102,126 @@ fn main() {
         )
         .subcommand(SubCommand::with_name("erase_memory_global"))
         .subcommand(SubCommand::with_name("erase_ext_all"))
+        .subcommand(
+            SubCommand::with_name("write_protect")
+                .arg(Arg::with_name("sector").required(true))
+                .arg(Arg::with_name("count").required(true)),
+        )
+        .subcommand(SubCommand::with_name("write_unprotect"))
+        .subcommand(
+            SubCommand::with_name("readout_protect").arg(
+                Arg::with_name("confirm")
+                    .long("confirm")
+                    .required(true)
+                    .help("Confirm locking the flash memory against readout (RDP level 1)"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("readout_unprotect").arg(
+                Arg::with_name("confirm")
+                    .long("confirm")
+                    .required(true)
+                    .help("Confirm removing readout protection, this mass erases the flash memory"),
+            ),
+        )
         .subcommand(
             SubCommand::with_name("write_file")
                 .arg(Arg::with_name("file").required(true))
-                .arg(Arg::with_name("address").default_value("0x08000000")),
+                .arg(
+                    Arg::with_name("address")
+                        .default_value("0x08000000")
+                        .help("Start address of raw binary files"),
+                )
+                .arg(skip_erased.clone())
+                .arg(fill_gaps.clone())
+                .arg(dry_run.clone()),
         )
         .subcommand(
             SubCommand::with_name("verify_file")
                 .arg(Arg::with_name("file").required(true))
-                .arg(Arg::with_name("address").default_value("0x08000000")),
+                .arg(
+                    Arg::with_name("address")
+                        .default_value("0x08000000")
+                        .help("Start address of raw binary files"),
+                )
+                .arg(skip_erased.clone()),
         )
         .subcommand(
             SubCommand::with_name("flash")
                 .arg(Arg::with_name("file").required(true))
-                .arg(Arg::with_name("address").default_value("0x08000000")),
+                .arg(
+                    Arg::with_name("address")
+                        .default_value("0x08000000")
+                        .help("Start address of raw binary files"),
+                )
+                .arg(
+                    Arg::with_name("erase")
+                        .short('e')
+                        .long("erase")
+                        .value_name("ERASE")
+                        .help(
+                            "What to erase before flashing, \"changed\" only erases and writes \
+                             sectors which differ from the file",
+                        )
+                        .takes_value(true)
+                        .possible_values(["mass", "sectors", "changed", "none"])
+                        .default_value("mass"),
+                )
+                .arg(
+                    Arg::with_name("expect-chip")
+                        .long("expect-chip")
+                        .value_name("CHIP")
+                        .help(
+                            "Abort before erasing unless the device is this chip, \
+                             a product ID like 0x413 or a family like STM32F4",
+                        )
+                        .takes_value(true)
+                        .validator(|s| s.parse::<ExpectedChip>().map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("check-vectors")
+                        .long("check-vectors")
+                        .value_name("CHECK")
+                        .help(
+                            "Check the stack pointer and reset vector of the file before \
+                             erasing, catches files linked for a different address",
+                        )
+                        .takes_value(true)
+                        .possible_values(["off", "warn", "abort"])
+                        .default_value("off"),
+                )
+                .arg(Arg::with_name("go").long("go").help(
+                    "Start the firmware after flashing, files with an entry point start there",
+                ))
+                .arg(skip_erased)
+                .arg(fill_gaps)
+                .arg(dry_run),
         )
         .subcommand(SubCommand::with_name("reset"))
+        .subcommand(
+            SubCommand::with_name("decode")
+                .about("Decodes captured bootloader traffic, no device is needed")
+                .arg(Arg::with_name("file").required(true))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Trace written by --trace or CSV, guessed from the extension")
+                        .takes_value(true)
+                        .possible_values(["trace", "csv"]),
+                ),
+        )
         .settings(&[
             clap::AppSettings::ArgRequiredElseHelp,
             clap::AppSettings::SubcommandRequiredElseHelp,
         ])
         .get_matches();
 
+    if let Some(("decode", sub_m)) = matches.subcommand() {
+        decode_capture(sub_m);
+        return;
+    }
+
     let port_name = matches.value_of("port").expect("missing port");
     let baud_rate = matches
         .value_of("baudrate")
@@ -131,18 +258,37 @@ fn main() {
         }
     }
 
-    if let Some("reset") = matches.subcommand_name() {
-        if let Some(gpio_boot) = &mut gpio_boot {
-            println!("Resetting boot pin");
-            gpio_boot.set_value(0).expect("Failed to reset boot pin");
+    let mut dry_run = false;
+    if let Some((name @ ("flash" | "write_file"), sub_m)) = matches.subcommand() {
+        if sub_m.is_present("dry-run") {
+            print_plan(sub_m, name == "flash", port_name, baud_rate);
+            dry_run = true;
         }
+    }
 
-        toggle_reset_opt(&mut gpio_reset);
+    if dry_run || matches.subcommand_name() == Some("reset") {
+        release_pins(&mut gpio_boot, &mut gpio_reset);
         return;
     }
 
     println!("Connecting on {} {}", port_name, baud_rate);
-    let mut port = connect_port(port_name, baud_rate).expect("Failed to connect");
+    // without a trace file the recorder writes to nowhere
+    let trace: Box<dyn std::io::Write> = match matches.value_of("trace") {
+        Some(path) => Box::new(std::fs::File::create(path).expect("Failed to create trace file")),
+        None => Box::new(std::io::sink()),
+    };
+    let mut trace = TraceWriter::new(trace);
+    trace
+        .comment(&format!("{} {} baud", port_name, baud_rate))
+        .expect("Failed to write trace");
+    let mut port = match connect(port_name, baud_rate, trace) {
+        Ok(port) => port,
+        Err(e) => {
+            println!("Failed to connect: {}", e);
+            release_pins(&mut gpio_boot, &mut gpio_reset);
+            return;
+        }
+    };
     println!("Connected on {}", port_name);
 
     match matches.subcommand() {
@@ -154,16 +300,31 @@ fn main() {
             let res = get_version(&mut port);
             println!("Version: {:?}", res);
         }
-        Some(("get_id", _)) => {
-            let res = get_id(&mut port);
-            println!("ID: {:?}", res);
-        }
+        Some(("get_id", _)) => match get_id(&mut port) {
+            Ok(pid) => match device::lookup(pid) {
+                Some(device) => {
+                    println!("ID: {:#05X} {}", pid, device);
+                    println!(
+                        "Option bytes at {:#010X}, bootloader RAM {:#010X}..{:#010X}",
+                        device.option_bytes, device.bootloader_ram.start, device.bootloader_ram.end
+                    );
+                }
+                None => println!("ID: {:#05X} unknown device", pid),
+            },
+            Err(e) => println!("ID: {:?}", e),
+        },
         Some(("read_memory", sub_m)) => {
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
             let size = sub_m.value_of("size").unwrap().parse().unwrap();
             let res = read_memory_vec(&mut port, address, size);
             println!("Memory: {:?}", res);
         }
+        Some(("get_checksum", sub_m)) => {
+            let address = parse(sub_m.value_of("address").unwrap()).unwrap();
+            let size = parse(sub_m.value_of("size").unwrap()).unwrap();
+            let res = get_checksum(&mut port, address, size);
+            println!("Checksum: {:#010x?}", res);
+        }
         Some(("go", sub_m)) => {
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
             let res = go(&mut port, address);
@@ -172,14 +333,19 @@ fn main() {
         Some(("write_memory", sub_m)) => {
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
             let data = sub_m.value_of("data").unwrap().as_bytes().to_vec();
-            let res = write_memory(&mut port, address, &data);
+            let options = write_options(&mut port, false);
+            // the session refuses writes to the RAM used by the bootloader
+            let res = Bootloader::attach(&mut port)
+                .and_then(|mut bootloader| bootloader.write_memory(address, &data, options));
             println!("Write: {:?}", res);
         }
         Some(("erase_memory", sub_m)) => {
             let page: u8 = sub_m.value_of("page").unwrap().parse().unwrap();
             let count: u8 = sub_m.value_of("count").unwrap().parse().unwrap();
-            let res = erase_memory(&mut port, &(page..page + count).collect::<Vec<u8>>());
-            println!("Erase: {:?}", res);
+            match sector_range(page, count) {
+                Some(pages) => println!("Erase: {:?}", erase_memory(&mut port, &pages)),
+                None => println!("Erase: {} pages from page {} go past page 255", count, page),
+            }
         }
         Some(("erase_memory_global", _)) => {
             let res = erase_memory_global(&mut port);
@@ -189,43 +355,131 @@ fn main() {
             let res = extended_erase_special(&mut port, SpecialEraseType::MassErase);
             println!("Erase ext all: {:?}", res);
         }
+        Some(("write_protect", sub_m)) => {
+            let sector: u8 = sub_m.value_of("sector").unwrap().parse().unwrap();
+            let count: u8 = sub_m.value_of("count").unwrap().parse().unwrap();
+            match sector_range(sector, count) {
+                Some(sectors) => {
+                    println!("Write protect: {:?}", write_protect(&mut port, &sectors))
+                }
+                None => println!(
+                    "Write protect: {} sectors from sector {} go past sector 255",
+                    count, sector
+                ),
+            }
+        }
+        Some(("write_unprotect", _)) => {
+            let res = write_unprotect(&mut port);
+            println!("Write unprotect: {:?}", res);
+        }
+        Some(("readout_protect", _)) => {
+            let res = readout_protect(&mut port);
+            println!("Readout protect: {:?}", res);
+        }
+        Some(("readout_unprotect", _)) => {
+            println!("Removing readout protection, this erases the flash memory");
+            let res = readout_unprotect(&mut port);
+            println!("Readout unprotect: {:?}", res);
+        }
         Some(("write_file", sub_m)) => {
             let file = sub_m.value_of("file").unwrap();
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
-            let res = flash_file(&mut port, file, address);
+            let options = write_options(&mut port, sub_m.is_present("skip-erased"));
+            let res = image::load_file(file, address).and_then(|mut image| {
+                if sub_m.is_present("fill-gaps") {
+                    image.fill_gaps(options.erased_value);
+                }
+                write_image(&mut port, &image, options)
+            });
             println!("Flash: {:?}", res);
         }
         Some(("verify_file", sub_m)) => {
             let file = sub_m.value_of("file").unwrap();
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
-            let res = verify_file(&mut port, file, address);
+            let options = write_options(&mut port, sub_m.is_present("skip-erased"));
+            let res = verify_file(&mut port, file, address, options);
             println!("Verify: {:?}", res);
         }
         Some(("flash", sub_m)) => {
             let file = sub_m.value_of("file").unwrap();
             let address = parse(sub_m.value_of("address").unwrap()).unwrap();
+            let erase: EraseStrategy = sub_m.value_of("erase").unwrap().parse().unwrap();
+            let expected: Option<ExpectedChip> =
+                sub_m.value_of("expect-chip").map(|s| s.parse().unwrap());
+            let check_vectors: VectorCheck =
+                sub_m.value_of("check-vectors").unwrap().parse().unwrap();
+            let options = write_options(&mut port, sub_m.is_present("skip-erased"));
+            match image::read_file(file, address) {
+                Err(e) => println!("Error reading {}: {}", file, e),
+                Ok((format, mut firmware)) => {
+                    // ELF files may also load into RAM, only the flash part is written
+                    if format == image::FileFormat::Elf {
+                        firmware.crop_to_flash(get_id(&mut port).ok().and_then(device::lookup));
+                    }
+                    if sub_m.is_present("fill-gaps") {
+                        firmware.fill_gaps(options.erased_value);
+                    }
+                    let config = FlashConfig {
+                        port: port_name.to_string(),
+                        baud_rate,
+                        address,
+                        erase,
+                        write: options,
+                        expected_chip: expected,
+                        check_vectors,
+                        ..Default::default()
+                    };
+                    match prepare_flash(&mut port, &firmware, &config) {
+                        Err(e) => println!("Not flashing: {}", e),
+                        Ok(prepared) => {
+                            if let Some(e) = &prepared.reconnect {
+                                println!("Reconnect after erase: {:?}", e);
+                                // close current port, the trace continues on the new one
+                                let (old, mut trace) = port.into_parts();
+                                drop(old);
 
-            // Note: this might time out for some reason, it does succeed anyway
-            println!("Erasing sectors");
-            let res = extended_erase_special(&mut port, SpecialEraseType::MassErase);
-            if let Err(e) = res {
-                println!("Reconnect after erase: {:?}", e);
-                // close current port
-                drop(port);
-
-                toggle_reset_opt(&mut gpio_reset);
-                port = connect_port(port_name, baud_rate).expect("Failed to connect");
-            }
+                                toggle_reset_opt(&mut gpio_reset);
+                                trace.comment("reconnect").expect("Failed to write trace");
+                                port = match connect(port_name, baud_rate, trace) {
+                                    Ok(port) => port,
+                                    Err(e) => {
+                                        println!("Failed to reconnect: {}", e);
+                                        release_pins(&mut gpio_boot, &mut gpio_reset);
+                                        return;
+                                    }
+                                };
+                            }
 
-            println!("Flashing {} at {:#010X}", file, address);
-            if let Err(e) = flash_file(&mut port, file, address) {
-                println!("Error flashing: {:?}", e);
-            } else {
-                println!("Writing done, verifying");
-                if let Err(e) = verify_file(&mut port, file, address) {
-                    println!("Error verifying: {:?}", e);
-                } else {
-                    println!("Flash Successful");
+                            let options = prepared.options;
+                            let write = prepared.changed.as_ref().unwrap_or(&firmware);
+                            for segment in write.segments() {
+                                println!(
+                                    "Flashing {} bytes of {} at {:#010X}",
+                                    segment.data.len(),
+                                    file,
+                                    segment.address
+                                );
+                            }
+                            if let Err(e) = write_image(&mut port, write, options) {
+                                println!("Error flashing: {:?}", e);
+                            } else {
+                                println!("Writing done, verifying");
+                                if let Err(e) = verify_image(&mut port, &firmware, options) {
+                                    println!("Error verifying: {:?}", e);
+                                } else {
+                                    println!("Flash Successful");
+                                    if sub_m.is_present("go") {
+                                        let go_address = firmware
+                                            .go_address()
+                                            .or(firmware.start())
+                                            .unwrap_or(address);
+                                        let res = go(&mut port, go_address);
+                                        println!("Go {:#010X}: {:?}", go_address, res);
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -235,12 +489,32 @@ fn main() {
         _ => (),
     }
 
-    if let Some(gpio_boot) = &mut gpio_boot {
+    release_pins(&mut gpio_boot, &mut gpio_reset);
+}
+
+type TracedPort = Recorder<Box<dyn SerialPort>, Box<dyn std::io::Write>>;
+
+// Opens the port and synchronises with the bootloader, the handshake is recorded as well
+fn connect(
+    port_name: &str,
+    baud_rate: u32,
+    trace: TraceWriter<Box<dyn std::io::Write>>,
+) -> std::io::Result<TracedPort> {
+    let port = open_port(port_name, baud_rate)?;
+    let mut port = Recorder::new(port, trace);
+    synchronise(&mut port)?;
+    port.get_mut().set_timeout(COMMAND_TIMEOUT)?;
+    Ok(port)
+}
+
+// Leaves the bootloader, the device starts the application after the reset
+fn release_pins(gpio_boot: &mut Option<GpioPin>, gpio_reset: &mut Option<GpioPin>) {
+    if let Some(gpio_boot) = gpio_boot {
         println!("Resetting boot pin");
         gpio_boot.set_value(0).expect("Failed to reset boot pin");
     }
 
-    toggle_reset_opt(&mut gpio_reset);
+    toggle_reset_opt(gpio_reset);
 }
 
 fn toggle_reset_opt(gpio_reset: &mut Option<GpioPin>) {
@@ -248,3 +522,126 @@ fn toggle_reset_opt(gpio_reset: &mut Option<GpioPin>) {
         toggle_reset(gpio_reset).expect("Failed to toggle reset pin");
     }
 }
+
+// Numbers of count sectors from first on, None if they do not fit in a byte
+fn sector_range(first: u8, count: u8) -> Option<Vec<u8>> {
+    let end = u16::from(first) + u16::from(count);
+    (end <= 256).then(|| (u16::from(first)..end).map(|sector| sector as u8).collect())
+}
+
+// Erased value and programming unit of the device, the defaults are used for unknown devices
+fn write_options<T: std::io::Read + std::io::Write>(
+    port: &mut T,
+    skip_erased: bool,
+) -> WriteOptions {
+    let mut options = match get_id(port).map(device::lookup) {
+        Ok(Some(device)) => WriteOptions::for_device(device),
+        _ => WriteOptions::default(),
+    };
+    println!("Writing in units of {} bytes", options.granularity);
+    if skip_erased {
+        options.skip = SkipPolicy::Erased;
+        println!("Skipping blocks of {:#04x}", options.erased_value);
+    }
+    options
+}
+
+// Prints the transactions of a capture
+fn decode_capture(sub_m: &ArgMatches) {
+    let file = sub_m.value_of("file").unwrap();
+    let csv = match sub_m.value_of("format") {
+        Some(format) => format == "csv",
+        None => file.to_ascii_lowercase().ends_with(".csv"),
+    };
+    let entries = std::fs::File::open(file)
+        .map(std::io::BufReader::new)
+        .and_then(|reader| {
+            if csv {
+                trace::read_csv(reader)
+            } else {
+                trace::read_trace(reader)
+            }
+        });
+    match entries {
+        Ok(entries) => {
+            for transaction in decode::decode(&entries) {
+                println!("{}", transaction);
+            }
+        }
+        Err(e) => println!("Error reading {}: {}", file, e),
+    }
+}
+
+// Prints the flash plan after the checks flashing runs, the device is only asked for
+// its commands and ID. Without a device the plan is made without knowing the flash layout.
+fn print_plan(sub_m: &ArgMatches, flash: bool, port_name: &str, baud_rate: u32) {
+    let file = sub_m.value_of("file").unwrap();
+    let address = parse(sub_m.value_of("address").unwrap()).unwrap();
+    let (format, mut image) = match image::read_file(file, address) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+    let mut config = FlashConfig {
+        port: port_name.to_string(),
+        baud_rate,
+        address,
+        // write_file does not erase
+        erase: EraseStrategy::None,
+        ..Default::default()
+    };
+    if flash {
+        config.erase = sub_m.value_of("erase").unwrap().parse().unwrap();
+        config.expected_chip = sub_m.value_of("expect-chip").map(|s| s.parse().unwrap());
+        config.check_vectors = sub_m.value_of("check-vectors").unwrap().parse().unwrap();
+    }
+    // flash writes only the flash part of ELF files
+    let crop = flash && format == image::FileFormat::Elf;
+
+    let plan = match connect_port(port_name, baud_rate) {
+        Ok(mut port) => {
+            config.write = write_options(&mut port, sub_m.is_present("skip-erased"));
+            if crop {
+                image.crop_to_flash(get_id(&mut port).ok().and_then(device::lookup));
+            }
+            if sub_m.is_present("fill-gaps") {
+                image.fill_gaps(config.write.erased_value);
+            }
+            if flash {
+                plan_flash(&mut port, &image, &config)
+            } else {
+                plan_write(&mut port, &image, config.write, baud_rate)
+            }
+        }
+        Err(e) => {
+            println!("No device connected: {}", e);
+            if sub_m.is_present("skip-erased") {
+                config.write.skip = SkipPolicy::Erased;
+            }
+            if crop {
+                image.crop_to_flash(None);
+            }
+            if sub_m.is_present("fill-gaps") {
+                image.fill_gaps(config.write.erased_value);
+            }
+            if config.expected_chip.is_some() {
+                println!("The chip can not be checked without the device");
+            }
+            // write_file does not check the image and does not verify
+            let (check_vectors, verify) = if flash {
+                (config.check_vectors, None)
+            } else {
+                (VectorCheck::Off, Some(plan::VerifyMethod::Off))
+            };
+            check_vectors.check(&image, None).and_then(|()| {
+                FlashPlan::new(&image, None, config.erase, config.write, verify, baud_rate)
+            })
+        }
+    };
+    match plan {
+        Ok(plan) => println!("{}", plan),
+        Err(e) => println!("Not flashing: {}", e),
+    }
+}
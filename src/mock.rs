@@ -0,0 +1,617 @@
+// Simulated bootloader speaking AN3155, for tests without hardware.
+// The device answers synchronously: every byte written is processed immediately and
+// the answer can be read right away. Reading when the device has nothing to send
+// fails with ErrorKind::TimedOut, like a serial port whose timeout expired.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::device::{self, Device, FlashLayout};
+use crate::protocol::{ACK, HELLO_BYTE, NACK};
+use crate::{crc, Command, Stage};
+
+// Commands a v3.1 bootloader with Extended Erase supports
+pub const DEFAULT_COMMANDS: &[u8] = &[
+    0x00, 0x01, 0x02, 0x11, 0x21, 0x31, 0x44, 0x63, 0x73, 0x82, 0x92,
+];
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub pid: u16,
+    pub version: u8,
+    // Opcodes listed by Get, other commands are answered with NACK
+    pub commands: Vec<u8>,
+    pub flash: FlashLayout,
+    pub erased_value: u8,
+    pub ram: Range<u32>,
+    // Time erasing a single page or sector takes
+    pub erase_time: Duration,
+}
+
+impl MockConfig {
+    pub fn for_device(device: &Device) -> Self {
+        MockConfig {
+            pid: device.pid,
+            version: 0x31,
+            commands: DEFAULT_COMMANDS.to_vec(),
+            flash: device.flash,
+            erased_value: device.erased_value(),
+            ram: device.ram[0].clone(),
+            erase_time: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for MockConfig {
+    // STM32F405/407/415/417
+    fn default() -> Self {
+        MockConfig::for_device(device::lookup(0x413).unwrap())
+    }
+}
+
+// Misbehaviour injected into the communication, each fault happens once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // NACK instead of the next ACK sent after the stage
+    Nack(Stage),
+    // No answer after the stage, the device waits for the next command
+    Timeout(Stage),
+    // The byte instead of the next ACK sent after the stage
+    Garbage(Stage, u8),
+    // The next Get Checksum answers a CRC which does not match the memory
+    WrongChecksum,
+    // The nth byte sent by the host from now on is lost
+    DropInput(usize),
+    // The nth byte sent by the device from now on is lost
+    DropOutput(usize),
+}
+
+// What the device expects next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Waiting for the hello byte after a reset
+    Sync,
+    Command,
+    Address(Command),
+    ReadLength(u32),
+    WriteData(u32),
+    ChecksumLength(u32),
+    Erase,
+    ExtendedErase,
+    WriteProtect,
+    // First packet of Special or Extended Special
+    Special(Command),
+    // Second packet of Extended Special
+    ExtendedSpecialData,
+    // The application was started with Go, nothing is answered anymore
+    Running,
+}
+
+pub struct MockDevice {
+    config: MockConfig,
+    flash: Vec<u8>,
+    ram: Vec<u8>,
+    write_protected: Vec<bool>,
+    readout_protected: bool,
+    jumped_to: Option<u32>,
+    resets: usize,
+    state: State,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    faults: Vec<Fault>,
+    // bytes received and sent so far, used to drop bytes
+    received: usize,
+    sent: usize,
+    drop_input: Vec<usize>,
+    drop_output: Vec<usize>,
+}
+
+impl MockDevice {
+    pub fn new(config: MockConfig) -> Self {
+        MockDevice {
+            flash: vec![config.erased_value; config.flash.size() as usize],
+            ram: vec![0; config.ram.len()],
+            write_protected: vec![false; config.flash.iter().count()],
+            readout_protected: false,
+            jumped_to: None,
+            resets: 0,
+            state: State::Sync,
+            input: Vec::new(),
+            output: VecDeque::new(),
+            faults: Vec::new(),
+            received: 0,
+            sent: 0,
+            drop_input: Vec::new(),
+            drop_output: Vec::new(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &MockConfig {
+        &self.config
+    }
+
+    pub fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::DropInput(n) => self.drop_input.push(self.received + n),
+            Fault::DropOutput(n) => self.drop_output.push(self.sent + n),
+            fault => self.faults.push(fault),
+        }
+    }
+
+    // Contents of flash memory or RAM, panics outside of them
+    pub fn memory(&self, address: u32, len: usize) -> &[u8] {
+        self.region(address, len).expect("address out of range")
+    }
+
+    // Places data in flash memory or RAM, bypassing erase and write protection
+    pub fn load(&mut self, address: u32, data: &[u8]) {
+        self.region_mut(address, data.len())
+            .expect("address out of range")
+            .copy_from_slice(data);
+    }
+
+    pub fn is_write_protected(&self, sector: u16) -> bool {
+        self.write_protected[sector as usize]
+    }
+
+    pub fn set_write_protected(&mut self, sector: u16, protected: bool) {
+        self.write_protected[sector as usize] = protected;
+    }
+
+    pub fn is_readout_protected(&self) -> bool {
+        self.readout_protected
+    }
+
+    pub fn set_readout_protected(&mut self, protected: bool) {
+        self.readout_protected = protected;
+    }
+
+    // Address passed to the last Go command
+    pub fn jumped_to(&self) -> Option<u32> {
+        self.jumped_to
+    }
+
+    // Number of system resets, e.g. after changing the protection
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    fn region(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let (memory, base) = if self.config.flash.contains(address) {
+            (&self.flash, self.config.flash.base)
+        } else if self.config.ram.contains(&address) {
+            (&self.ram, self.config.ram.start)
+        } else {
+            return None;
+        };
+        let offset = (address - base) as usize;
+        memory.get(offset..offset + len)
+    }
+
+    fn region_mut(&mut self, address: u32, len: usize) -> Option<&mut [u8]> {
+        let (memory, base) = if self.config.flash.contains(address) {
+            (&mut self.flash, self.config.flash.base)
+        } else if self.config.ram.contains(&address) {
+            (&mut self.ram, self.config.ram.start)
+        } else {
+            return None;
+        };
+        let offset = (address - base) as usize;
+        memory.get_mut(offset..offset + len)
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if let Some(i) = self.drop_output.iter().position(|&n| n == self.sent) {
+                self.drop_output.remove(i);
+            } else {
+                self.output.push_back(byte);
+            }
+            self.sent += 1;
+        }
+    }
+
+    fn nack(&mut self) {
+        self.send(&[NACK]);
+        self.state = State::Command;
+    }
+
+    // Sends the ACK after the stage unless a fault is injected there.
+    // Returns false if the command was aborted.
+    fn ack(&mut self, stage: Stage) -> bool {
+        let fault = self.faults.iter().position(|fault| {
+            matches!(fault, Fault::Nack(s) | Fault::Timeout(s) | Fault::Garbage(s, _) if *s == stage)
+        });
+        match fault.map(|i| self.faults.remove(i)) {
+            None => {
+                self.send(&[ACK]);
+                true
+            }
+            Some(fault) => {
+                match fault {
+                    Fault::Nack(_) => self.send(&[NACK]),
+                    Fault::Garbage(_, byte) => self.send(&[byte]),
+                    _ => (),
+                }
+                self.state = State::Command;
+                false
+            }
+        }
+    }
+
+    // System reset, the bootloader waits for the hello byte again
+    fn reset(&mut self) {
+        self.state = State::Sync;
+        self.resets += 1;
+    }
+
+    // Number of bytes the current step needs, None if more bytes are needed to tell
+    fn frame_len(&self) -> Option<usize> {
+        let input = &self.input;
+        let len = match self.state {
+            State::Sync | State::Running => 1,
+            State::Command if input.first() == Some(&HELLO_BYTE) => 1,
+            State::Command | State::ReadLength(_) => 2,
+            State::Address(_) | State::ChecksumLength(_) => 5,
+            State::WriteData(_) | State::WriteProtect => *input.first()? as usize + 3,
+            State::Erase => match *input.first()? {
+                0xFF => 2,
+                n => n as usize + 3,
+            },
+            State::ExtendedErase => {
+                let n = u16::from_be_bytes([*input.first()?, *input.get(1)?]);
+                if n >= 0xFFF0 {
+                    3
+                } else {
+                    2 * (n as usize + 1) + 3
+                }
+            }
+            State::Special(_) => u16::from_be_bytes([*input.get(2)?, *input.get(3)?]) as usize + 5,
+            State::ExtendedSpecialData => {
+                u16::from_be_bytes([*input.first()?, *input.get(1)?]) as usize + 3
+            }
+        };
+        Some(len)
+    }
+
+    fn receive(&mut self, byte: u8) {
+        let dropped = self.drop_input.iter().position(|&n| n == self.received);
+        self.received += 1;
+        if let Some(i) = dropped {
+            self.drop_input.remove(i);
+            return;
+        }
+        self.input.push(byte);
+        if self.frame_len() == Some(self.input.len()) {
+            let frame = std::mem::take(&mut self.input);
+            self.handle(&frame);
+        }
+    }
+
+    fn handle(&mut self, frame: &[u8]) {
+        match self.state {
+            State::Sync => {
+                if frame[0] == HELLO_BYTE && self.ack(Stage::Command) {
+                    self.state = State::Command;
+                }
+            }
+            State::Running => (),
+            State::Command => self.handle_command(frame),
+            State::Address(command) => self.handle_address(command, frame),
+            State::ReadLength(address) => self.handle_read(address, frame),
+            State::WriteData(address) => self.handle_write(address, frame),
+            State::ChecksumLength(address) => self.handle_checksum(address, frame),
+            State::Erase => self.handle_erase(frame),
+            State::ExtendedErase => self.handle_extended_erase(frame),
+            State::WriteProtect => self.handle_write_protect(frame),
+            State::Special(command) => self.handle_special(command, frame),
+            State::ExtendedSpecialData => self.handle_extended_special_data(frame),
+        }
+    }
+
+    fn handle_command(&mut self, frame: &[u8]) {
+        if frame == [HELLO_BYTE] {
+            self.ack(Stage::Command);
+            return;
+        }
+        let command = match Command::from_opcode(frame[0]) {
+            Some(command) if frame[1] == !frame[0] && self.config.commands.contains(&frame[0]) => {
+                command
+            }
+            _ => return self.nack(),
+        };
+        let blocked = matches!(
+            command,
+            Command::ReadMemory
+                | Command::WriteMemory
+                | Command::Go
+                | Command::Erase
+                | Command::ExtendedErase
+                | Command::GetChecksum
+        );
+        if self.readout_protected && blocked {
+            return self.nack();
+        }
+        if !self.ack(Stage::Command) {
+            return;
+        }
+
+        self.state = State::Command;
+        match command {
+            Command::Get => {
+                let mut response = vec![self.config.commands.len() as u8, self.config.version];
+                response.extend_from_slice(&self.config.commands);
+                self.send(&response);
+                self.ack(Stage::Response);
+            }
+            Command::GetVersion => {
+                self.send(&[self.config.version, 0, 0]);
+                self.ack(Stage::Response);
+            }
+            Command::GetId => {
+                let pid = self.config.pid.to_be_bytes();
+                self.send(&[1, pid[0], pid[1]]);
+                self.ack(Stage::Response);
+            }
+            Command::ReadMemory | Command::Go | Command::WriteMemory | Command::GetChecksum => {
+                self.state = State::Address(command)
+            }
+            Command::Erase => self.state = State::Erase,
+            Command::ExtendedErase => self.state = State::ExtendedErase,
+            Command::WriteProtect => self.state = State::WriteProtect,
+            Command::Special | Command::ExtendedSpecial => self.state = State::Special(command),
+            Command::WriteUnprotect => {
+                self.write_protected.fill(false);
+                self.ack(Stage::Completion);
+                self.reset();
+            }
+            Command::ReadoutProtect => {
+                self.readout_protected = true;
+                self.ack(Stage::Completion);
+                self.reset();
+            }
+            Command::ReadoutUnprotect => {
+                sleep(self.config.erase_time * self.write_protected.len() as u32);
+                self.flash.fill(self.config.erased_value);
+                self.readout_protected = false;
+                self.ack(Stage::Completion);
+                self.reset();
+            }
+            Command::Hello => (),
+        }
+    }
+
+    fn handle_address(&mut self, command: Command, frame: &[u8]) {
+        let address = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        if xor(&frame[..4]) != frame[4] || self.region(address, 1).is_none() {
+            return self.nack();
+        }
+        if !self.ack(Stage::Address) {
+            return;
+        }
+        self.state = match command {
+            Command::ReadMemory => State::ReadLength(address),
+            Command::WriteMemory => State::WriteData(address),
+            Command::GetChecksum => State::ChecksumLength(address),
+            _ => {
+                self.jumped_to = Some(address);
+                State::Running
+            }
+        };
+    }
+
+    fn handle_read(&mut self, address: u32, frame: &[u8]) {
+        let len = frame[0] as usize + 1;
+        if frame[1] != !frame[0] || self.region(address, len).is_none() {
+            return self.nack();
+        }
+        if !self.ack(Stage::Length) {
+            return;
+        }
+        let data = self.memory(address, len).to_vec();
+        self.send(&data);
+        self.state = State::Command;
+    }
+
+    fn handle_write(&mut self, address: u32, frame: &[u8]) {
+        let data = &frame[1..frame.len() - 1];
+        if xor(&frame[..frame.len() - 1]) != frame[frame.len() - 1] || !self.writable(address, data)
+        {
+            return self.nack();
+        }
+        if !self.ack(Stage::Data) {
+            return;
+        }
+        self.load(address, data);
+        self.state = State::Command;
+    }
+
+    // Flash memory can only be written if it is erased or already holds the data
+    fn writable(&self, address: u32, data: &[u8]) -> bool {
+        let Some(memory) = self.region(address, data.len()) else {
+            return false;
+        };
+        if !self.config.flash.contains(address) {
+            return true;
+        }
+        let protected = self
+            .config
+            .flash
+            .sectors_covering(address, data.len())
+            .iter()
+            .any(|sector| self.write_protected[sector.index as usize]);
+        let erased = self.config.erased_value;
+        !protected
+            && memory
+                .iter()
+                .zip(data)
+                .all(|(&old, &new)| old == erased || old == new)
+    }
+
+    fn handle_checksum(&mut self, address: u32, frame: &[u8]) {
+        let len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+        let valid = xor(&frame[..4]) == frame[4]
+            && address.is_multiple_of(4)
+            && len.is_multiple_of(4)
+            && len > 0;
+        if !valid || self.region(address, len).is_none() {
+            return self.nack();
+        }
+        if !self.ack(Stage::Length) {
+            return;
+        }
+        let mut crc = crc::stm32_crc32(self.memory(address, len));
+        if let Some(i) = self.faults.iter().position(|&f| f == Fault::WrongChecksum) {
+            self.faults.remove(i);
+            crc = !crc;
+        }
+        let crc = crc.to_be_bytes();
+        self.send(&crc);
+        self.send(&[xor(&crc)]);
+        self.state = State::Command;
+    }
+
+    fn handle_erase(&mut self, frame: &[u8]) {
+        if frame == [0xFF, 0x00] {
+            return self.erase_pages(0..self.write_protected.len() as u16);
+        }
+        if xor(&frame[..frame.len() - 1]) != frame[frame.len() - 1] {
+            return self.nack();
+        }
+        let pages = frame[1..frame.len() - 1].iter().map(|&page| page as u16);
+        self.erase_pages(pages);
+    }
+
+    fn handle_extended_erase(&mut self, frame: &[u8]) {
+        if xor(&frame[..frame.len() - 1]) != frame[frame.len() - 1] {
+            return self.nack();
+        }
+        let count = self.write_protected.len() as u16;
+        match u16::from_be_bytes([frame[0], frame[1]]) {
+            0xFFFF => self.erase_pages(0..count),
+            0xFFFE => self.erase_pages(0..count / 2),
+            0xFFFD => self.erase_pages(count / 2..count),
+            n if n >= 0xFFF0 => self.nack(),
+            _ => {
+                let pages = frame[2..frame.len() - 1]
+                    .chunks(2)
+                    .map(|page| u16::from_be_bytes([page[0], page[1]]))
+                    .collect::<Vec<_>>();
+                self.erase_pages(pages);
+            }
+        }
+    }
+
+    fn erase_pages(&mut self, pages: impl IntoIterator<Item = u16>) {
+        let sectors = self.config.flash.iter().collect::<Vec<_>>();
+        let pages = pages.into_iter().collect::<Vec<_>>();
+        let valid = pages
+            .iter()
+            .all(|&page| (page as usize) < sectors.len() && !self.write_protected[page as usize]);
+        if !valid {
+            return self.nack();
+        }
+        sleep(self.config.erase_time * pages.len() as u32);
+        if !self.ack(Stage::Data) {
+            return;
+        }
+        let base = self.config.flash.base;
+        for page in pages {
+            let sector = sectors[page as usize];
+            let start = (sector.address - base) as usize;
+            self.flash[start..start + sector.size as usize].fill(self.config.erased_value);
+        }
+        self.state = State::Command;
+    }
+
+    fn handle_write_protect(&mut self, frame: &[u8]) {
+        let sectors = &frame[1..frame.len() - 1];
+        let valid = xor(&frame[..frame.len() - 1]) == frame[frame.len() - 1]
+            && sectors
+                .iter()
+                .all(|&sector| (sector as usize) < self.write_protected.len());
+        if !valid {
+            return self.nack();
+        }
+        if !self.ack(Stage::Data) {
+            return;
+        }
+        for &sector in sectors {
+            self.write_protected[sector as usize] = true;
+        }
+        self.reset();
+    }
+
+    // The request data is sent back as response data, the status is empty
+    fn handle_special(&mut self, command: Command, frame: &[u8]) {
+        if xor(&frame[..frame.len() - 1]) != frame[frame.len() - 1] {
+            return self.nack();
+        }
+        if command == Command::ExtendedSpecial {
+            if self.ack(Stage::Length) {
+                self.state = State::ExtendedSpecialData;
+            }
+            return;
+        }
+        if !self.ack(Stage::Data) {
+            return;
+        }
+        // length and data as received
+        let data = frame[2..frame.len() - 1].to_vec();
+        self.send(&data);
+        self.send(&[0, 0]);
+        self.ack(Stage::Response);
+        self.state = State::Command;
+    }
+
+    fn handle_extended_special_data(&mut self, frame: &[u8]) {
+        if xor(&frame[..frame.len() - 1]) != frame[frame.len() - 1] {
+            return self.nack();
+        }
+        if !self.ack(Stage::Data) {
+            return;
+        }
+        self.send(&[0, 0]);
+        self.ack(Stage::Response);
+        self.state = State::Command;
+    }
+}
+
+fn xor(data: &[u8]) -> u8 {
+    data.iter().fold(0, |acc, &x| acc ^ x)
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "mock device did not answer",
+            ));
+        }
+        let len = std::cmp::min(buf.len(), self.output.len());
+        for (dst, src) in buf.iter_mut().zip(self.output.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.receive(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
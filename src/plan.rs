@@ -0,0 +1,250 @@
+// What flashing an image will do, computed without writing to the device
+use std::fmt;
+use std::time::Duration;
+
+use crate::device::{Device, Sector};
+use crate::{
+    aligned_range, write_blocks, BootloaderError, Command, EraseStrategy, MemoryImage,
+    WriteOptions, CHECKSUM_CHUNK_SIZE, WRITE_ALIGNMENT,
+};
+
+// UART frame of one byte: start bit, 8 data bits, parity and stop bit
+const BITS_PER_BYTE: u64 = 11;
+// Bytes of a Write Memory or Read Memory command besides the data:
+// command, address, length, checksum and ACKs
+const BLOCK_OVERHEAD: usize = 12;
+// Bytes of a Get Checksum command
+const CHECKSUM_COMMAND_SIZE: usize = 21;
+// Bytes of an erase command besides the page numbers
+const ERASE_OVERHEAD: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedErase {
+    Mass,
+    Pages(Vec<Sector>),
+    // Only the pages which differ from the image, found by comparing them before erasing
+    ChangedPages(Vec<Sector>),
+    // Pages or sectors were requested but the flash layout of the device is not known
+    UnknownPages,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMethod {
+    Checksum,
+    Readback,
+    // Nothing is verified, as with write_image
+    Off,
+}
+
+impl VerifyMethod {
+    // Method Bootloader::verify_memory uses with the commands listed by Get
+    pub fn for_commands(commands: &[u8]) -> Self {
+        if commands.contains(&Command::GetChecksum.opcode()) {
+            VerifyMethod::Checksum
+        } else {
+            VerifyMethod::Readback
+        }
+    }
+}
+
+// Block sent with one Write Memory command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBlock {
+    pub address: u32,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashPlan {
+    pub device: Option<&'static Device>,
+    pub erase: PlannedErase,
+    pub write: Vec<WriteBlock>,
+    // Blocks left out according to the skip policy, they are not verified either
+    pub skipped: Vec<WriteBlock>,
+    // None if it is not known whether the bootloader supports Get Checksum
+    pub verify: Option<VerifyMethod>,
+    pub baud_rate: u32,
+}
+
+impl FlashPlan {
+    // Plans erasing, writing and verifying the image the same way Flasher::flash_image does.
+    // The device is needed to plan erasing pages or sectors, options should already contain
+    // its programming unit.
+    pub fn new(
+        image: &MemoryImage,
+        device: Option<&'static Device>,
+        erase: EraseStrategy,
+        options: WriteOptions,
+        verify: Option<VerifyMethod>,
+        baud_rate: u32,
+    ) -> Result<Self, BootloaderError> {
+        if options.granularity > WRITE_ALIGNMENT {
+            image.check_granularity(options.granularity)?;
+        }
+        let erase = match (erase, device) {
+            (EraseStrategy::Mass, _) => PlannedErase::Mass,
+            (EraseStrategy::None, _) => PlannedErase::None,
+            (_, None) => PlannedErase::UnknownPages,
+            (EraseStrategy::CoveredSectorsOnly, Some(device)) => {
+                PlannedErase::Pages(device.flash.sectors_covering_image(image))
+            }
+            (EraseStrategy::ChangedSectorsOnly, Some(device)) => {
+                PlannedErase::ChangedPages(device.flash.sectors_covering_image(image))
+            }
+        };
+
+        let mut write = Vec::new();
+        let mut skipped = Vec::new();
+        for segment in image
+            .joined(WRITE_ALIGNMENT, options.erased_value)
+            .segments()
+        {
+            let (start, head, len) = aligned_range(segment.address, segment.data.len(), options);
+            // the memory in front of an unaligned start is unknown, assume it is erased
+            let mut data = vec![options.erased_value; head];
+            data.extend_from_slice(&segment.data);
+            data.resize(len, options.erased_value);
+            for (address, block, skip) in write_blocks(start, &data, options) {
+                let block = WriteBlock {
+                    address,
+                    len: block.len(),
+                };
+                if skip {
+                    skipped.push(block);
+                } else {
+                    write.push(block);
+                }
+            }
+        }
+
+        Ok(FlashPlan {
+            device,
+            erase,
+            write,
+            skipped,
+            verify,
+            baud_rate,
+        })
+    }
+
+    // Number of bytes written
+    pub fn write_len(&self) -> usize {
+        self.write.iter().map(|block| block.len).sum()
+    }
+
+    // UART transfer time of all commands, the time the device needs to erase
+    // and program the memory is not included
+    pub fn estimated_duration(&self) -> Duration {
+        // the comparison before erasing changed pages checks all blocks, skipped ones as well
+        let compare = match &self.erase {
+            PlannedErase::ChangedPages(_) => {
+                let mut blocks = [self.write.as_slice(), self.skipped.as_slice()].concat();
+                blocks.sort_by_key(|block| block.address);
+                self.verify_size(&blocks)
+            }
+            _ => 0,
+        };
+        let erase = match &self.erase {
+            PlannedErase::Mass => ERASE_OVERHEAD,
+            PlannedErase::Pages(pages) | PlannedErase::ChangedPages(pages) => {
+                ERASE_OVERHEAD + 2 * pages.len()
+            }
+            PlannedErase::UnknownPages | PlannedErase::None => 0,
+        };
+        let write = self.write_len() + BLOCK_OVERHEAD * self.write.len();
+        let verify = self.verify_size(&self.write);
+        let bytes = (compare + erase + write + verify) as u64;
+        Duration::from_millis(bytes * BITS_PER_BYTE * 1000 / self.baud_rate.max(1) as u64)
+    }
+
+    // Bytes transferred to verify the blocks
+    fn verify_size(&self, blocks: &[WriteBlock]) -> usize {
+        match self.verify {
+            Some(VerifyMethod::Checksum) => {
+                // consecutive blocks are verified together
+                let mut commands = 0;
+                let mut run: usize = 0;
+                let mut end = None;
+                for block in blocks {
+                    if end != Some(block.address) {
+                        commands += run.div_ceil(CHECKSUM_CHUNK_SIZE);
+                        run = 0;
+                    }
+                    run += block.len;
+                    end = Some(block.address + block.len as u32);
+                }
+                commands += run.div_ceil(CHECKSUM_CHUNK_SIZE);
+                commands * CHECKSUM_COMMAND_SIZE
+            }
+            Some(VerifyMethod::Off) => 0,
+            // the slower method, if it is not known which one is used
+            Some(VerifyMethod::Readback) | None => {
+                blocks.iter().map(|block| block.len + BLOCK_OVERHEAD).sum()
+            }
+        }
+    }
+}
+
+fn fmt_sectors(f: &mut fmt::Formatter<'_>, sectors: &[Sector]) -> fmt::Result {
+    let indices = sectors
+        .iter()
+        .map(|sector| sector.index)
+        .collect::<Vec<_>>();
+    let size: u32 = sectors.iter().map(|sector| sector.size).sum();
+    write!(f, "{} pages ({} bytes): {:?}", sectors.len(), size, indices)
+}
+
+impl fmt::Display for FlashPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device {
+            Some(device) => writeln!(f, "Device: {}", device)?,
+            None => writeln!(f, "Device: unknown")?,
+        }
+        write!(f, "Erase: ")?;
+        match &self.erase {
+            PlannedErase::Mass => writeln!(f, "mass erase")?,
+            PlannedErase::Pages(sectors) => {
+                fmt_sectors(f, sectors)?;
+                writeln!(f)?;
+            }
+            PlannedErase::ChangedPages(sectors) => {
+                write!(f, "changed ones of ")?;
+                fmt_sectors(f, sectors)?;
+                writeln!(f)?;
+            }
+            PlannedErase::UnknownPages => writeln!(f, "pages unknown without the device")?,
+            PlannedErase::None => writeln!(f, "nothing")?,
+        }
+        writeln!(
+            f,
+            "Write: {} blocks, {} bytes",
+            self.write.len(),
+            self.write_len()
+        )?;
+        // consecutive blocks as one range
+        let mut ranges: Vec<(u32, u64)> = Vec::new();
+        for block in &self.write {
+            match ranges.last_mut() {
+                Some((_, end)) if *end == block.address as u64 => *end += block.len as u64,
+                _ => ranges.push((block.address, block.address as u64 + block.len as u64)),
+            }
+        }
+        for (start, end) in ranges {
+            writeln!(f, "  {:#010X}..{:#010X}", start, end)?;
+        }
+        writeln!(f, "Skip: {} blocks", self.skipped.len())?;
+        match self.verify {
+            Some(VerifyMethod::Checksum) => writeln!(f, "Verify: Get Checksum")?,
+            Some(VerifyMethod::Readback) => writeln!(f, "Verify: read back")?,
+            Some(VerifyMethod::Off) => writeln!(f, "Verify: nothing")?,
+            None => writeln!(f, "Verify: Get Checksum if supported, otherwise read back")?,
+        }
+        write!(
+            f,
+            "Estimated transfer time: {:.1} s at {} baud",
+            self.estimated_duration().as_secs_f32(),
+            self.baud_rate
+        )
+    }
+}
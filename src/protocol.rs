@@ -0,0 +1,547 @@
+// Sans-IO implementation of the AN3155 protocol. An Exchange runs one command: it
+// yields the bytes to send and the number of bytes it waits for, consumes the answer
+// of the device in pieces of any size and finally yields the decoded response.
+// It does no I/O itself, so it works with any transport. The blocking functions of
+// the crate drive it over Read + Write, see execute.
+//
+//   let mut exchange = Exchange::new(Request::GetId)?;
+//   loop {
+//       match exchange.poll() {
+//           Event::Send(data) => port.write_all(&data)?,
+//           Event::Receive(len) => exchange.receive(&read(len)?)?,
+//           Event::Done(response) => break response,
+//       };
+//   }
+use std::collections::VecDeque;
+
+use crate::{BootloaderError, Command, SpecialEraseType, SpecialResponse, Stage, WRITE_ALIGNMENT};
+
+pub const ACK: u8 = 0x79;
+pub const NACK: u8 = 0x1F;
+pub const HELLO_BYTE: u8 = 0x7F;
+
+const SPECIAL_MAX_DATA: usize = 128;
+const EXTENDED_SPECIAL_MAX_DATA: usize = 1024;
+
+// XOR of all bytes, starting with initial
+pub fn checksum(data: &[u8], initial: u8) -> u8 {
+    data.iter().fold(initial, |acc, &x| acc ^ x)
+}
+
+// Opcode followed by its complement
+pub fn encode_command(command: Command) -> [u8; 2] {
+    [command.opcode(), !command.opcode()]
+}
+
+// Address or size of a memory area followed by its checksum
+pub fn encode_word(word: u32) -> [u8; 5] {
+    let [a, b, c, d] = word.to_be_bytes();
+    [a, b, c, d, a ^ b ^ c ^ d]
+}
+
+// Number of bytes minus one followed by its complement, as sent by Read Memory
+pub fn encode_read_length(len: usize) -> [u8; 2] {
+    let len = (len - 1) as u8;
+    [len, !len]
+}
+
+// Number of bytes minus one, the bytes and the checksum, as sent by Write Memory,
+// Erase Memory and Write Protect
+pub fn encode_block(data: &[u8]) -> Vec<u8> {
+    let len = (data.len() - 1) as u8;
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.push(len);
+    buf.extend_from_slice(data);
+    buf.push(checksum(data, len));
+    buf
+}
+
+// Number of pages minus one, the page numbers and the checksum, as sent by Extended Erase
+pub fn encode_pages(pages: &[u16]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(pages.len() * 2 + 3);
+    buf.extend_from_slice(&((pages.len() - 1) as u16).to_be_bytes());
+    for page in pages {
+        buf.extend_from_slice(&page.to_be_bytes());
+    }
+    buf.push(checksum(&buf, 0));
+    buf
+}
+
+// Special erase code of Extended Erase followed by its checksum
+pub fn encode_special_erase(erase: SpecialEraseType) -> [u8; 3] {
+    let [a, b] = (erase as u16).to_be_bytes();
+    [a, b, a ^ b]
+}
+
+// Optional opcode, 2 byte length, data and checksum, as sent by the Special commands
+pub fn encode_special(opcode: Option<u16>, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 5);
+    if let Some(opcode) = opcode {
+        buf.extend_from_slice(&opcode.to_be_bytes());
+    }
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    buf.push(checksum(&buf, 0));
+    buf
+}
+
+// A command with its arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Hello,
+    Get,
+    GetVersion,
+    GetId,
+    ReadMemory {
+        address: u32,
+        len: usize,
+    },
+    Go {
+        address: u32,
+    },
+    WriteMemory {
+        address: u32,
+        data: Vec<u8>,
+    },
+    Erase {
+        sectors: Vec<u8>,
+    },
+    GlobalErase,
+    ExtendedErase {
+        pages: Vec<u16>,
+    },
+    SpecialErase(SpecialEraseType),
+    WriteProtect {
+        sectors: Vec<u8>,
+    },
+    WriteUnprotect,
+    ReadoutProtect,
+    ReadoutUnprotect,
+    GetChecksum {
+        address: u32,
+        len: u32,
+    },
+    Special {
+        opcode: u16,
+        data: Vec<u8>,
+    },
+    ExtendedSpecial {
+        opcode: u16,
+        data: Vec<u8>,
+        data2: Vec<u8>,
+    },
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::Hello => Command::Hello,
+            Request::Get => Command::Get,
+            Request::GetVersion => Command::GetVersion,
+            Request::GetId => Command::GetId,
+            Request::ReadMemory { .. } => Command::ReadMemory,
+            Request::Go { .. } => Command::Go,
+            Request::WriteMemory { .. } => Command::WriteMemory,
+            Request::Erase { .. } | Request::GlobalErase => Command::Erase,
+            Request::ExtendedErase { .. } | Request::SpecialErase(_) => Command::ExtendedErase,
+            Request::WriteProtect { .. } => Command::WriteProtect,
+            Request::WriteUnprotect => Command::WriteUnprotect,
+            Request::ReadoutProtect => Command::ReadoutProtect,
+            Request::ReadoutUnprotect => Command::ReadoutUnprotect,
+            Request::GetChecksum { .. } => Command::GetChecksum,
+            Request::Special { .. } => Command::Special,
+            Request::ExtendedSpecial { .. } => Command::ExtendedSpecial,
+        }
+    }
+
+    pub fn address(&self) -> Option<u32> {
+        match self {
+            Request::ReadMemory { address, .. }
+            | Request::Go { address }
+            | Request::WriteMemory { address, .. }
+            | Request::GetChecksum { address, .. } => Some(*address),
+            _ => None,
+        }
+    }
+
+    // Checks the lengths and alignment the command supports
+    pub fn validate(&self) -> Result<(), BootloaderError> {
+        let command = self.command();
+        let invalid_length = |len| Err(BootloaderError::InvalidLength { command, len });
+        let unaligned = |address| Err(BootloaderError::Unaligned { command, address });
+        match self {
+            Request::ReadMemory { len, .. } if *len == 0 || *len > 256 => invalid_length(*len),
+            Request::WriteMemory { data, .. }
+                if data.is_empty()
+                    || data.len() > 256
+                    || !data.len().is_multiple_of(WRITE_ALIGNMENT) =>
+            {
+                invalid_length(data.len())
+            }
+            Request::WriteMemory { address, .. }
+                if !address.is_multiple_of(WRITE_ALIGNMENT as u32) =>
+            {
+                unaligned(*address)
+            }
+            Request::Erase { sectors } if sectors.is_empty() || sectors.len() > 255 => {
+                invalid_length(sectors.len())
+            }
+            Request::ExtendedErase { pages } if pages.is_empty() || pages.len() >= 0xFFF0 => {
+                invalid_length(pages.len())
+            }
+            Request::WriteProtect { sectors } if sectors.is_empty() || sectors.len() > 256 => {
+                invalid_length(sectors.len())
+            }
+            Request::GetChecksum { len, .. } if *len == 0 || !len.is_multiple_of(4) => {
+                invalid_length(*len as usize)
+            }
+            Request::GetChecksum { address, .. } if !address.is_multiple_of(4) => {
+                unaligned(*address)
+            }
+            Request::Special { data, .. } if data.len() > SPECIAL_MAX_DATA => {
+                invalid_length(data.len())
+            }
+            Request::ExtendedSpecial { data, data2, .. }
+                if data.len() > SPECIAL_MAX_DATA || data2.len() > EXTENDED_SPECIAL_MAX_DATA =>
+            {
+                invalid_length(std::cmp::max(data.len(), data2.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The frames sent and received in order
+    fn steps(&self) -> VecDeque<Step> {
+        use Step::*;
+        let command = Send(encode_command(self.command()).to_vec());
+        let steps = match self {
+            Request::Hello => vec![Send(vec![HELLO_BYTE]), Ack(Stage::Command)],
+            Request::Get => vec![
+                command,
+                Ack(Stage::Command),
+                CountPrefixed,
+                Ack(Stage::Response),
+            ],
+            Request::GetVersion => {
+                vec![command, Ack(Stage::Command), Read(3), Ack(Stage::Response)]
+            }
+            Request::GetId => vec![
+                command,
+                Ack(Stage::Command),
+                CountPrefixed,
+                Ack(Stage::Response),
+            ],
+            Request::ReadMemory { address, len } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_word(*address).to_vec()),
+                Ack(Stage::Address),
+                Send(encode_read_length(*len).to_vec()),
+                Ack(Stage::Length),
+                Read(*len),
+            ],
+            Request::Go { address } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_word(*address).to_vec()),
+                Ack(Stage::Address),
+            ],
+            Request::WriteMemory { address, data } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_word(*address).to_vec()),
+                Ack(Stage::Address),
+                Send(encode_block(data)),
+                Ack(Stage::Data),
+            ],
+            Request::Erase { sectors } | Request::WriteProtect { sectors } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_block(sectors)),
+                Ack(Stage::Data),
+            ],
+            // 0xFF00 means global erase
+            Request::GlobalErase => vec![
+                command,
+                Ack(Stage::Command),
+                Send(vec![0xFF, 0x00]),
+                Ack(Stage::Data),
+            ],
+            Request::ExtendedErase { pages } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_pages(pages)),
+                Ack(Stage::Data),
+            ],
+            Request::SpecialErase(erase) => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_special_erase(*erase).to_vec()),
+                Ack(Stage::Data),
+            ],
+            Request::WriteUnprotect | Request::ReadoutProtect | Request::ReadoutUnprotect => {
+                vec![command, Ack(Stage::Command), Ack(Stage::Completion)]
+            }
+            Request::GetChecksum { address, len } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_word(*address).to_vec()),
+                Ack(Stage::Address),
+                Send(encode_word(*len).to_vec()),
+                Ack(Stage::Length),
+                Read(5),
+            ],
+            Request::Special { opcode, data } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_special(Some(*opcode), data)),
+                Ack(Stage::Data),
+                LengthPrefixed,
+                LengthPrefixed,
+                Ack(Stage::Response),
+            ],
+            Request::ExtendedSpecial {
+                opcode,
+                data,
+                data2,
+            } => vec![
+                command,
+                Ack(Stage::Command),
+                Send(encode_special(Some(*opcode), data)),
+                Ack(Stage::Length),
+                Send(encode_special(None, data2)),
+                Ack(Stage::Data),
+                LengthPrefixed,
+                Ack(Stage::Response),
+            ],
+        };
+        steps.into()
+    }
+}
+
+// Decoded answer of the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    // The command has no response besides the ACKs
+    Ack,
+    Get { version: u8, commands: Vec<u8> },
+    Version(u8),
+    Id(u16),
+    Data(Vec<u8>),
+    Checksum(u32),
+    Special(SpecialResponse),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // Bytes to send to the device
+    Send(Vec<u8>),
+    // Number of bytes the exchange waits for, they are passed to receive
+    Receive(usize),
+    // The command completed
+    Done(Response),
+}
+
+#[derive(Debug)]
+enum Step {
+    Send(Vec<u8>),
+    Ack(Stage),
+    // Fixed number of response bytes
+    Read(usize),
+    // Number of bytes minus one followed by the bytes
+    CountPrefixed,
+    // 2 byte big endian length followed by the bytes
+    LengthPrefixed,
+}
+
+// State machine running one command
+#[derive(Debug)]
+pub struct Exchange {
+    command: Command,
+    address: Option<u32>,
+    steps: VecDeque<Step>,
+    // bytes of the current read step received so far
+    buf: Vec<u8>,
+    // completed reads
+    fields: Vec<Vec<u8>>,
+    response: Option<Response>,
+}
+
+impl Exchange {
+    pub fn new(request: Request) -> Result<Self, BootloaderError> {
+        request.validate()?;
+        Ok(Exchange::unchecked(request))
+    }
+
+    // Runs the request without validating it, to replay what another host sent
+    pub(crate) fn unchecked(request: Request) -> Self {
+        Exchange {
+            command: request.command(),
+            address: request.address(),
+            steps: request.steps(),
+            buf: Vec::new(),
+            fields: Vec::new(),
+            response: None,
+        }
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn address(&self) -> Option<u32> {
+        self.address
+    }
+
+    // Stage the exchange waits in, to report a timeout
+    pub fn stage(&self) -> Stage {
+        match self.steps.front() {
+            Some(Step::Ack(stage)) => *stage,
+            _ => Stage::Response,
+        }
+    }
+
+    // True if the next byte received is an ACK or NACK
+    pub(crate) fn waits_for_ack(&self) -> bool {
+        matches!(self.steps.front(), Some(Step::Ack(_)))
+    }
+
+    // Returns what to do next. Panics if polled again after Done.
+    pub fn poll(&mut self) -> Event {
+        let wanted = match self.steps.front() {
+            Some(Step::Send(_)) => {
+                let Some(Step::Send(data)) = self.steps.pop_front() else {
+                    unreachable!()
+                };
+                return Event::Send(data);
+            }
+            Some(Step::Ack(_)) => 1,
+            Some(Step::Read(len)) => *len,
+            Some(Step::CountPrefixed) => 1,
+            Some(Step::LengthPrefixed) => 2,
+            None => {
+                return Event::Done(
+                    self.response
+                        .take()
+                        .expect("exchange polled after it completed"),
+                )
+            }
+        };
+        Event::Receive(wanted - self.buf.len())
+    }
+
+    // Consumes bytes received from the device and returns how many were used.
+    // Bytes are only used while the exchange waits for them, so the return value
+    // is less than the length if the device sent more than expected.
+    pub fn receive(&mut self, data: &[u8]) -> Result<usize, BootloaderError> {
+        let mut used = 0;
+        while used < data.len() {
+            let wanted = match self.steps.front() {
+                Some(Step::Ack(stage)) => {
+                    let stage = *stage;
+                    self.ack(stage, data[used])?;
+                    used += 1;
+                    self.steps.pop_front();
+                    continue;
+                }
+                Some(Step::Read(len)) => *len,
+                Some(Step::CountPrefixed) => 1,
+                Some(Step::LengthPrefixed) => 2,
+                Some(Step::Send(_)) | None => break,
+            };
+            let len = std::cmp::min(wanted - self.buf.len(), data.len() - used);
+            self.buf.extend_from_slice(&data[used..used + len]);
+            used += len;
+            if self.buf.len() == wanted {
+                let bytes = std::mem::take(&mut self.buf);
+                match self.steps.pop_front() {
+                    Some(Step::CountPrefixed) => {
+                        self.steps.push_front(Step::Read(bytes[0] as usize + 1))
+                    }
+                    Some(Step::LengthPrefixed) => {
+                        match u16::from_be_bytes([bytes[0], bytes[1]]) as usize {
+                            0 => self.fields.push(Vec::new()),
+                            len => self.steps.push_front(Step::Read(len)),
+                        }
+                    }
+                    _ => self.fields.push(bytes),
+                }
+            }
+        }
+        if self.steps.is_empty() && self.response.is_none() {
+            self.response = Some(self.decode()?);
+        }
+        Ok(used)
+    }
+
+    fn ack(&self, stage: Stage, byte: u8) -> Result<(), BootloaderError> {
+        let command = self.command;
+        let address = self.address;
+        match byte {
+            ACK => Ok(()),
+            NACK => Err(BootloaderError::Nack {
+                command,
+                stage,
+                address,
+            }),
+            byte => Err(BootloaderError::UnexpectedByte {
+                command,
+                stage,
+                address,
+                byte,
+            }),
+        }
+    }
+
+    // Builds the response once all steps completed
+    fn decode(&mut self) -> Result<Response, BootloaderError> {
+        let mut fields = std::mem::take(&mut self.fields).into_iter();
+        let mut field = || fields.next().unwrap_or_default();
+        let response = match self.command {
+            Command::Get => {
+                let data = field();
+                Response::Get {
+                    version: data[0],
+                    commands: data[1..].to_vec(),
+                }
+            }
+            Command::GetVersion => Response::Version(field()[0]),
+            Command::GetId => {
+                let id = field();
+                if id.len() != 2 {
+                    return Err(BootloaderError::UnexpectedByte {
+                        command: self.command,
+                        stage: Stage::Response,
+                        address: self.address,
+                        // number of bytes minus one
+                        byte: (id.len() - 1) as u8,
+                    });
+                }
+                Response::Id(u16::from_be_bytes([id[0], id[1]]))
+            }
+            Command::ReadMemory => Response::Data(field()),
+            Command::GetChecksum => {
+                let data = field();
+                if checksum(&data[..4], 0) != data[4] {
+                    return Err(BootloaderError::UnexpectedByte {
+                        command: self.command,
+                        stage: Stage::Response,
+                        address: self.address,
+                        byte: data[4],
+                    });
+                }
+                Response::Checksum(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            Command::Special => Response::Special(SpecialResponse {
+                data: field(),
+                status: field(),
+            }),
+            Command::ExtendedSpecial => Response::Special(SpecialResponse {
+                data: Vec::new(),
+                status: field(),
+            }),
+            _ => Response::Ack,
+        };
+        Ok(response)
+    }
+}
@@ -0,0 +1,100 @@
+// Motorola S-record reader
+// https://en.wikipedia.org/wiki/SREC_(file_format)
+use crate::image::{decode_hex, FileFormat, MemoryImage, Segment};
+use crate::BootloaderError;
+
+fn error(line: usize, reason: &str) -> BootloaderError {
+    BootloaderError::InvalidFile {
+        format: FileFormat::SRecord,
+        reason: format!("line {}: {}", line, reason),
+    }
+}
+
+fn be_address(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &x| (acc << 8) | x as u32)
+}
+
+// Parses the records of an S-record file.
+// S1/S2/S3 data records are placed at their address, the S5/S6 count records are
+// checked against the number of data records read so far and S7/S8/S9 set the entry point.
+pub fn parse(text: &str) -> Result<MemoryImage, BootloaderError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut entry = None;
+    let mut data_records: u32 = 0;
+    let mut terminated = false;
+
+    for (i, record) in text.lines().enumerate() {
+        let line = i + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        if terminated {
+            return Err(error(line, "data after termination record"));
+        }
+        let record_type = record
+            .strip_prefix('S')
+            .and_then(|rest| rest.chars().next())
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| error(line, "missing record type"))?;
+        let bytes = decode_hex(&record[2..]).ok_or_else(|| error(line, "invalid hex digits"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error(line, "invalid record length"));
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |acc, &x| acc.wrapping_add(x));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(error(line, "checksum mismatch"));
+        }
+
+        let address_len = match record_type {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => {
+                return Err(error(
+                    line,
+                    &format!("unknown record type S{}", record_type),
+                ))
+            }
+        };
+        let payload = &bytes[1..bytes.len() - 1];
+        if payload.len() < address_len {
+            return Err(error(line, "invalid record length"));
+        }
+        let address = be_address(&payload[..address_len]);
+        let data = &payload[address_len..];
+        match record_type {
+            // header
+            0 => {}
+            1..=3 => {
+                data_records += 1;
+                match segments.last_mut() {
+                    Some(last) if last.end() == address as u64 => last.data.extend_from_slice(data),
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            5 | 6 => {
+                if address != data_records {
+                    return Err(error(
+                        line,
+                        &format!(
+                            "record count {} does not match {} data records",
+                            address, data_records
+                        ),
+                    ));
+                }
+            }
+            _ => {
+                entry = Some(address);
+                terminated = true;
+            }
+        }
+    }
+
+    MemoryImage::from_segments(FileFormat::SRecord, segments, entry)
+}
@@ -0,0 +1,344 @@
+// Recording of the bytes exchanged with the bootloader, and replaying them.
+// A trace is a text file with one event per line: the time in seconds since the
+// recording started, the direction and the bytes in hex.
+//
+//   0.000012 > 02 FD
+//   0.001040 < 79 01 04 13 79
+//   1.001512 ! timeout
+//
+// ">" is sent by the host, "<" is received from the device and "!" is a read which
+// timed out. Lines starting with "#" are comments.
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::image::decode_hex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Sent(Vec<u8>),
+    Received(Vec<u8>),
+    Timeout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub time: Duration,
+    pub event: Event,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6} ", self.time.as_secs_f64())?;
+        let data = match &self.event {
+            Event::Sent(data) => {
+                f.write_str(">")?;
+                data
+            }
+            Event::Received(data) => {
+                f.write_str("<")?;
+                data
+            }
+            Event::Timeout => return f.write_str("! timeout"),
+        };
+        for byte in data {
+            write!(f, " {:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for TraceEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(3, ' ');
+        let time = fields
+            .next()
+            .and_then(|time| time.parse::<f64>().ok())
+            .filter(|time| *time >= 0.0)
+            .ok_or_else(|| format!("Invalid time: {}", s))?;
+        let direction = fields.next().unwrap_or_default();
+        let data = fields.next().unwrap_or_default();
+        let bytes = || decode_hex(&data.replace(' ', "")).ok_or(format!("Invalid data: {}", s));
+        let event = match direction {
+            ">" => Event::Sent(bytes()?),
+            "<" => Event::Received(bytes()?),
+            "!" => Event::Timeout,
+            _ => return Err(format!("Invalid direction: {}", s)),
+        };
+        Ok(TraceEntry {
+            time: Duration::from_secs_f64(time),
+            event,
+        })
+    }
+}
+
+// Reads all entries of a trace
+pub fn read_trace<R: BufRead>(reader: R) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Reads a capture exported as CSV with one byte per line: the time in seconds,
+// "host" or "device" and the byte in hex. A header line is skipped.
+//
+//   time,direction,byte
+//   0.000012,host,0x02
+//   0.000013,host,0xFD
+//   0.001040,device,0x79
+pub fn read_csv<R: BufRead>(reader: R) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        if line.trim().is_empty() || (i == 0 && fields[0].parse::<f64>().is_err()) {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid line: {}", line),
+            )
+        };
+        let [time, direction, byte] = fields[..] else {
+            return Err(invalid());
+        };
+        let time = time
+            .parse::<f64>()
+            .ok()
+            .filter(|time| *time >= 0.0)
+            .ok_or_else(invalid)?;
+        let byte = u8::from_str_radix(byte.trim_start_matches("0x"), 16).map_err(|_| invalid())?;
+        let event = match direction.to_ascii_lowercase().as_str() {
+            "host" => Event::Sent(vec![byte]),
+            "device" => Event::Received(vec![byte]),
+            _ => return Err(invalid()),
+        };
+        entries.push(TraceEntry {
+            time: Duration::from_secs_f64(time),
+            event,
+        });
+    }
+    Ok(entries)
+}
+
+// Writes trace entries, times are relative to the creation of the writer
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl TraceWriter<File> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(TraceWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TraceWriter {
+            writer,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        writeln!(self.writer, "# {}", text)?;
+        self.writer.flush()
+    }
+
+    // Every entry is flushed, so the trace is complete even if the tool crashes
+    pub fn record(&mut self, event: Event) -> io::Result<()> {
+        let entry = TraceEntry {
+            time: self.start.elapsed(),
+            event,
+        };
+        writeln!(self.writer, "{}", entry)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Transport recording everything sent to and received from the wrapped port
+pub struct Recorder<T, W: Write> {
+    port: T,
+    trace: TraceWriter<W>,
+}
+
+impl<T: Read + Write, W: Write> Recorder<T, W> {
+    pub fn new(port: T, trace: TraceWriter<W>) -> Self {
+        Recorder { port, trace }
+    }
+
+    // The recorded port, e.g. to change its settings
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    // Separates port and trace, e.g. to continue the trace on a new connection
+    pub fn into_parts(self) -> (T, TraceWriter<W>) {
+        (self.port, self.trace)
+    }
+}
+
+impl<T: Read, W: Write> Read for Recorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.port.read(buf) {
+            Ok(len) => {
+                if len > 0 {
+                    self.trace.record(Event::Received(buf[..len].to_vec()))?;
+                }
+                Ok(len)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    self.trace.record(Event::Timeout)?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: Write, W: Write> Write for Recorder<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.port.write(buf)?;
+        if len > 0 {
+            self.trace.record(Event::Sent(buf[..len].to_vec()))?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+// Transport playing back a trace. Received data is returned in the recorded order
+// and the data the host sends is compared with the recorded data, so the library
+// takes the same path it took when the trace was recorded. Timing is not reproduced.
+pub struct Replay {
+    entries: VecDeque<TraceEntry>,
+    // bytes of the first entry already consumed
+    offset: usize,
+    // number of the current entry, for error messages
+    index: usize,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<TraceEntry>) -> Self {
+        Replay {
+            entries: entries.into(),
+            offset: 0,
+            index: 0,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let entries = read_trace(BufReader::new(File::open(path)?))?;
+        Ok(Replay::new(entries))
+    }
+
+    // True once every entry has been played back
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn advance(&mut self) {
+        self.entries.pop_front();
+        self.offset = 0;
+        self.index += 1;
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = match self.entries.front().map(|entry| &entry.event) {
+            Some(Event::Received(data)) => &data[self.offset..],
+            Some(Event::Timeout) => {
+                self.advance();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout in trace"));
+            }
+            // the device does not answer before the host sent the recorded data
+            Some(Event::Sent(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("trace entry {} expects data from the host", self.index),
+                ))
+            }
+            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "end of trace")),
+        };
+        let len = std::cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        let finished = len == data.len();
+        self.offset += len;
+        if finished {
+            self.advance();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let data = match self.entries.front().map(|entry| &entry.event) {
+                Some(Event::Sent(data)) => &data[self.offset..],
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "host sent {:02X?} but trace entry {} does not expect it",
+                            &buf[written..],
+                            self.index
+                        ),
+                    ))
+                }
+            };
+            let len = std::cmp::min(buf.len() - written, data.len());
+            if buf[written..written + len] != data[..len] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "host sent {:02X?} but trace entry {} has {:02X?}",
+                        &buf[written..written + len],
+                        self.index,
+                        &data[..len]
+                    ),
+                ));
+            }
+            written += len;
+            self.offset += len;
+            if len == data.len() {
+                self.advance();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
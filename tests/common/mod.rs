@@ -0,0 +1,21 @@
+// Helpers shared by the integration tests, not every test uses all of them
+#![allow(dead_code)]
+
+use stm32_firmware_loader::mock::{MockConfig, MockDevice};
+use stm32_firmware_loader::*;
+
+// Simulated device that already received the hello byte
+pub fn device() -> MockDevice {
+    device_with(MockConfig::default())
+}
+
+pub fn device_with(config: MockConfig) -> MockDevice {
+    let mut device = MockDevice::new(config);
+    hello(&mut device).unwrap();
+    device
+}
+
+// Test data without long runs of equal bytes
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
@@ -0,0 +1,205 @@
+use std::io::Cursor;
+use std::time::Duration;
+
+use stm32_firmware_loader::decode::{decode, Item};
+use stm32_firmware_loader::mock::{MockConfig, MockDevice};
+use stm32_firmware_loader::trace::{
+    read_csv, read_trace, Event, Recorder, TraceEntry, TraceWriter,
+};
+use stm32_firmware_loader::*;
+
+const FLASH: u32 = 0x0800_0000;
+
+fn entry(event: Event) -> TraceEntry {
+    TraceEntry {
+        time: Duration::ZERO,
+        event,
+    }
+}
+
+#[test]
+fn decode_session() {
+    let mut config = MockConfig {
+        version: 0x33,
+        ..Default::default()
+    };
+    config.commands.push(Command::GetChecksum.opcode());
+    let mut port = Recorder::new(MockDevice::new(config), TraceWriter::new(Vec::new()));
+    hello(&mut port).unwrap();
+    get(&mut port).unwrap();
+    get_id(&mut port).unwrap();
+    extended_erase(&mut port, &[0, 1]).unwrap();
+    write_memory_block(&mut port, FLASH, &[1, 2, 3, 4]).unwrap();
+    read_memory_vec(&mut port, FLASH, 4).unwrap();
+    get_checksum(&mut port, FLASH, 4).unwrap();
+    extended_erase_special(&mut port, SpecialEraseType::MassErase).unwrap();
+    go(&mut port, FLASH).unwrap();
+
+    let (_, trace) = port.into_parts();
+    let entries = read_trace(Cursor::new(trace.into_inner())).unwrap();
+    let transactions = decode(&entries);
+
+    let commands = transactions
+        .iter()
+        .map(|transaction| transaction.command.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        [
+            Command::Hello,
+            Command::Get,
+            Command::GetId,
+            Command::ExtendedErase,
+            Command::WriteMemory,
+            Command::ReadMemory,
+            Command::GetChecksum,
+            Command::ExtendedErase,
+            Command::Go,
+        ]
+    );
+    assert!(transactions.iter().all(|t| t.violations.is_empty()));
+
+    assert_eq!(transactions[2].items[1], Item::ProductId(0x413));
+    assert_eq!(transactions[3].items[1], Item::Pages(vec![0, 1]));
+    assert_eq!(
+        transactions[4].items,
+        [
+            Item::Ack,
+            Item::Address(FLASH),
+            Item::Checksum {
+                value: 0x08,
+                valid: true
+            },
+            Item::Ack,
+            Item::Length(4),
+            Item::Data(vec![1, 2, 3, 4]),
+            Item::Checksum {
+                value: 0x03 ^ 0x04,
+                valid: true
+            },
+            Item::Ack,
+        ]
+    );
+    assert_eq!(
+        transactions[5].items.last(),
+        Some(&Item::Data(vec![1, 2, 3, 4]))
+    );
+    assert!(transactions[6]
+        .items
+        .contains(&Item::Crc(crc::stm32_crc32(&[1, 2, 3, 4]))));
+    assert_eq!(transactions[7].items[1], Item::MassErase);
+}
+
+#[test]
+fn decode_violations() {
+    let entries = [
+        // wrong address checksum, answered with NACK
+        entry(Event::Sent(vec![0x21, 0xDE])),
+        entry(Event::Received(vec![0x79])),
+        entry(Event::Sent(vec![0x08, 0x00, 0x00, 0x00, 0x00])),
+        entry(Event::Received(vec![0x1F])),
+        // wrong complement and no answer
+        entry(Event::Sent(vec![0x02, 0xFF])),
+        entry(Event::Sent(vec![0x02, 0xFD])),
+        entry(Event::Received(vec![0x79, 0x01, 0x04, 0x13, 0x79])),
+        // unsolicited byte
+        entry(Event::Received(vec![0x55])),
+    ];
+    let transactions = decode(&entries);
+    assert_eq!(transactions.len(), 4);
+
+    assert_eq!(transactions[0].command, Some(Command::Go));
+    assert_eq!(transactions[0].violations, ["wrong checksum"]);
+    assert_eq!(transactions[0].items.last(), Some(&Item::Nack));
+
+    assert_eq!(
+        transactions[1].violations,
+        ["wrong complement 0xFF", "no answer from the device"]
+    );
+    assert!(transactions[2].violations.is_empty());
+    assert_eq!(transactions[3].command, None);
+    assert_eq!(
+        transactions[3].violations,
+        ["unexpected byte 0x55 from the device"]
+    );
+}
+
+#[test]
+fn csv_capture() {
+    let csv = "time,direction,byte\n0.5,host,0x7F\n0.6,device,79\n";
+    let entries = read_csv(Cursor::new(csv)).unwrap();
+    assert_eq!(
+        entries,
+        [
+            TraceEntry {
+                time: Duration::from_millis(500),
+                event: Event::Sent(vec![0x7F]),
+            },
+            TraceEntry {
+                time: Duration::from_millis(600),
+                event: Event::Received(vec![0x79]),
+            },
+        ]
+    );
+    assert_eq!(decode(&entries)[0].items, [Item::Ack]);
+    assert!(read_csv(Cursor::new("0.5,somebody,0x7F\n")).is_err());
+}
+
+#[test]
+fn decode_invalid_answers() {
+    let entries = [
+        // Get ID answered with a 3 byte ID
+        entry(Event::Sent(vec![0x02, 0xFD])),
+        entry(Event::Received(vec![0x79, 0x02, 0x04, 0x13, 0x00, 0x79])),
+        // Get Checksum with a wrong checksum of the CRC
+        entry(Event::Sent(vec![0xA1, 0x5E])),
+        entry(Event::Received(vec![0x79])),
+        entry(Event::Sent(vec![0x08, 0x00, 0x00, 0x00, 0x08])),
+        entry(Event::Received(vec![0x79])),
+        entry(Event::Sent(vec![0x00, 0x00, 0x00, 0x04, 0x04])),
+        entry(Event::Received(vec![0x79, 0x12, 0x34, 0x56, 0x78, 0x00])),
+        // Special with an empty answer
+        entry(Event::Sent(vec![0x50, 0xAF])),
+        entry(Event::Received(vec![0x79])),
+        entry(Event::Sent(vec![0x01, 0x02, 0x00, 0x00, 0x03])),
+        entry(Event::Received(vec![0x79, 0x00, 0x00, 0x00, 0x00, 0x79])),
+    ];
+    let transactions = decode(&entries);
+    assert_eq!(transactions.len(), 3);
+
+    assert_eq!(
+        transactions[0].items,
+        [Item::Ack, Item::Data(vec![0x04, 0x13, 0x00]), Item::Ack]
+    );
+    assert_eq!(transactions[0].violations, ["product ID of 3 bytes"]);
+
+    assert_eq!(
+        transactions[1].items[transactions[1].items.len() - 2..],
+        [
+            Item::Crc(0x1234_5678),
+            Item::Checksum {
+                value: 0x00,
+                valid: false
+            }
+        ]
+    );
+    assert_eq!(transactions[1].violations, ["wrong checksum of the CRC"]);
+
+    assert_eq!(
+        transactions[2].items,
+        [
+            Item::Ack,
+            Item::Opcode(0x0102),
+            Item::Data(vec![]),
+            Item::Checksum {
+                value: 0x03,
+                valid: true
+            },
+            Item::Ack,
+            Item::Data(vec![]),
+            Item::Status(vec![]),
+            Item::Ack,
+        ]
+    );
+    assert!(transactions[2].violations.is_empty());
+}
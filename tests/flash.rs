@@ -0,0 +1,377 @@
+mod common;
+
+use std::io::{self, Cursor, Read, Write};
+
+use stm32_firmware_loader::mock::{Fault, MockConfig, MockDevice};
+use stm32_firmware_loader::protocol::encode_command;
+use stm32_firmware_loader::trace::{self, read_trace, Recorder, TraceWriter};
+use stm32_firmware_loader::*;
+
+use common::{device, device_with, pattern};
+
+const FLASH: u32 = 0x0800_0000;
+
+// Answers Get ID with NACK, all other commands reach the device
+struct NackGetId(MockDevice);
+
+impl Read for NackGetId {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for NackGetId {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf == encode_command(Command::GetId) {
+            self.0.inject(Fault::Nack(Stage::Command));
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn unidentified_chip_is_not_flashed() {
+    let mut device = device();
+    let firmware = pattern(256);
+    device.load(FLASH, &firmware);
+    let mut port = NackGetId(device);
+
+    let image = MemoryImage::from_binary(FLASH, vec![0x55; 256]);
+    let config = FlashConfig {
+        expected_chip: Some("STM32F4".parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        prepare_flash(&mut port, &image, &config),
+        Err(BootloaderError::Nack {
+            command: Command::GetId,
+            ..
+        })
+    ));
+    // neither erased nor written
+    assert_eq!(port.0.memory(FLASH, 256), firmware);
+}
+
+#[test]
+fn erase_timeout_continues_after_reconnect() {
+    let mut device = device();
+    device.inject(Fault::Timeout(Stage::Data));
+    let image = MemoryImage::from_binary(FLASH, pattern(256));
+    let prepared = prepare_flash(&mut device, &image, &FlashConfig::default()).unwrap();
+    assert!(matches!(
+        prepared.reconnect,
+        Some(BootloaderError::Timeout {
+            command: Command::ExtendedErase,
+            stage: Stage::Data,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn erase_errors_abort() {
+    let mut device = device();
+    device.set_write_protected(0, true);
+    let image = MemoryImage::from_binary(FLASH, pattern(256));
+    let config = FlashConfig {
+        erase: EraseStrategy::CoveredSectorsOnly,
+        ..Default::default()
+    };
+    assert!(matches!(
+        prepare_flash(&mut device, &image, &config),
+        Err(BootloaderError::Nack {
+            command: Command::ExtendedErase,
+            stage: Stage::Data,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn image_outside_flash() {
+    let mut bootloader = Bootloader::attach(device()).unwrap();
+    // STM32F405/407 with 1 MiB flash
+    let flash_end = FLASH + 0x10_0000;
+
+    let image = MemoryImage::from_binary(flash_end - 4, vec![0; 8]);
+    assert!(matches!(
+        bootloader.check_target(None, &image),
+        Err(BootloaderError::ImageTooLarge { end, flash_end: 0x0810_0000 })
+            if end == flash_end as u64 + 4
+    ));
+
+    let mut image = MemoryImage::from_binary(FLASH, vec![0; 8]);
+    image.insert(FLASH - 0x100, &[0; 4]).unwrap();
+    assert!(matches!(
+        bootloader.check_target(None, &image),
+        Err(BootloaderError::OutsideFlash {
+            address: 0x07FF_FF00,
+            ..
+        })
+    ));
+
+    let image = MemoryImage::from_binary(FLASH, vec![0; 8]);
+    assert_eq!(
+        bootloader.check_target(None, &image).unwrap().unwrap().pid,
+        0x413
+    );
+}
+
+#[test]
+fn write_granularity() {
+    let granularity = |pid| device::lookup(pid).unwrap().write_granularity();
+    assert_eq!(granularity(0x413), 4);
+    assert_eq!(granularity(0x450), 32);
+    // STM32H7A3/B3 program 128-bit flash words
+    assert_eq!(granularity(0x480), 16);
+    let options = WriteOptions::for_device(device::lookup(0x480).unwrap());
+    assert_eq!(options.granularity, 16);
+}
+
+#[test]
+fn plan_runs_checks() {
+    let image = MemoryImage::from_binary(FLASH, pattern(1024));
+    let config = FlashConfig {
+        expected_chip: Some("STM32H7".parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        plan_flash(&mut device(), &image, &config),
+        Err(BootloaderError::WrongChip { pid: 0x413, .. })
+    ));
+
+    let plan = plan_flash(&mut device(), &image, &FlashConfig::default()).unwrap();
+    assert_eq!(plan.verify, Some(plan::VerifyMethod::Readback));
+    let mut config = MockConfig::default();
+    config.commands.push(Command::GetChecksum.opcode());
+    let plan = plan_flash(&mut device_with(config), &image, &FlashConfig::default()).unwrap();
+    assert_eq!(plan.verify, Some(plan::VerifyMethod::Checksum));
+}
+
+#[test]
+fn plan_includes_comparison() {
+    let image = MemoryImage::from_binary(FLASH, pattern(4096));
+    let plan = |erase| {
+        let config = FlashConfig {
+            erase,
+            ..Default::default()
+        };
+        plan_flash(&mut device(), &image, &config).unwrap()
+    };
+    let covered = plan(EraseStrategy::CoveredSectorsOnly);
+    let changed = plan(EraseStrategy::ChangedSectorsOnly);
+    assert_eq!(covered.write, changed.write);
+    // the image is read back once more to compare it
+    let readback = covered.estimated_duration().as_millis() * 3 / 2;
+    assert!(changed.estimated_duration().as_millis() >= readback);
+}
+
+#[test]
+fn unaligned_h7_writes() {
+    let h7 = device::lookup(0x450).unwrap();
+    let mut device = device_with(MockConfig::for_device(h7));
+    let options = WriteOptions::for_device(h7);
+
+    // the rest of the flash word is padded with the erased value
+    write_memory(&mut device, FLASH + 8, &[1, 2, 3, 4], options).unwrap();
+    let mut expected = vec![0xFF; 32];
+    expected[8..12].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(device.memory(FLASH, 32), expected);
+
+    // the flash word was programmed already
+    assert!(matches!(
+        write_memory(&mut device, FLASH + 16, &[5, 6, 7, 8], options),
+        Err(BootloaderError::PartialProgramUnit {
+            address: FLASH,
+            granularity: 32,
+        })
+    ));
+    assert_eq!(device.memory(FLASH, 32), expected);
+}
+
+#[test]
+fn changed_sectors_only() {
+    let mut device = device();
+    // sectors 0 and 1 of 16 KiB
+    let firmware = pattern(0x8000);
+    device.load(FLASH, &firmware);
+    let mut image = MemoryImage::from_binary(FLASH, firmware.clone());
+    image.entry = Some(FLASH);
+    let mut changed = firmware.clone();
+    changed[0x4010] ^= 0xFF;
+    let mut new_image = MemoryImage::from_binary(FLASH, changed.clone());
+    new_image.entry = Some(FLASH);
+
+    let mut bootloader = Bootloader::attach(&mut device).unwrap();
+    let diff = bootloader
+        .diff_sectors(&image, WriteOptions::default())
+        .unwrap();
+    assert!(diff.changed.is_empty());
+    let diff = bootloader
+        .diff_sectors(&new_image, WriteOptions::default())
+        .unwrap();
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].index, 1);
+    let changed_image = diff.changed_image(&new_image).unwrap();
+    assert_eq!(
+        changed_image,
+        new_image.cropped(FLASH + 0x4000..FLASH + 0x8000)
+    );
+    assert_eq!(changed_image.entry, Some(FLASH));
+
+    let config = FlashConfig {
+        erase: EraseStrategy::ChangedSectorsOnly,
+        ..Default::default()
+    };
+    let prepared = prepare_flash(&mut device, &new_image, &config).unwrap();
+    let changed_image = prepared.changed.unwrap();
+    assert_eq!(changed_image.segments()[0].address, FLASH + 0x4000);
+    // only sector 1 was erased
+    assert_eq!(device.memory(FLASH, 0x4000), &firmware[..0x4000]);
+    assert!(device
+        .memory(FLASH + 0x4000, 0x4000)
+        .iter()
+        .all(|&x| x == 0xFF));
+    write_image(&mut device, &changed_image, prepared.options).unwrap();
+    verify_image(&mut device, &new_image, prepared.options).unwrap();
+}
+
+#[test]
+fn flash_plan() {
+    let f4 = device::lookup(0x413);
+    let mut image = MemoryImage::from_binary(FLASH + 2, pattern(300));
+    image.insert(FLASH + 0x4000, &[0xFF; 256]).unwrap();
+    let options = WriteOptions {
+        skip: SkipPolicy::Erased,
+        ..Default::default()
+    };
+    let plan = FlashPlan::new(
+        &image,
+        f4,
+        EraseStrategy::CoveredSectorsOnly,
+        options,
+        None,
+        115200,
+    )
+    .unwrap();
+    let pages = |plan: &FlashPlan| match &plan.erase {
+        plan::PlannedErase::Pages(sectors) => sectors
+            .iter()
+            .map(|sector| sector.index)
+            .collect::<Vec<_>>(),
+        erase => panic!("unexpected erase {:?}", erase),
+    };
+    assert_eq!(pages(&plan), [0, 1]);
+    // the unaligned start is written from the previous word on
+    let write = plan
+        .write
+        .iter()
+        .map(|block| (block.address, block.len))
+        .collect::<Vec<_>>();
+    assert_eq!(write, [(FLASH, 256), (FLASH + 256, 48)]);
+    assert_eq!(plan.skipped.len(), 1);
+    assert_eq!(plan.write_len(), 304);
+
+    let plan = FlashPlan::new(
+        &image,
+        None,
+        EraseStrategy::CoveredSectorsOnly,
+        options,
+        None,
+        115200,
+    )
+    .unwrap();
+    assert_eq!(plan.erase, plan::PlannedErase::UnknownPages);
+    // both blocks share a programming unit of an H7
+    let mut image = MemoryImage::from_binary(FLASH, pattern(8));
+    image.insert(FLASH + 16, &pattern(8)).unwrap();
+    let h7 = device::lookup(0x450).unwrap();
+    assert!(matches!(
+        FlashPlan::new(
+            &image,
+            Some(h7),
+            EraseStrategy::Mass,
+            WriteOptions::for_device(h7),
+            None,
+            115200
+        ),
+        Err(BootloaderError::PartialProgramUnit { .. })
+    ));
+}
+
+#[test]
+fn write_plan_for_ram() {
+    const RAM: u32 = 0x2000_4000;
+    let image = MemoryImage::from_binary(RAM, pattern(64));
+    let config = FlashConfig {
+        erase: EraseStrategy::None,
+        ..Default::default()
+    };
+    // flash only accepts images in flash memory
+    assert!(matches!(
+        plan_flash(&mut device(), &image, &config),
+        Err(BootloaderError::OutsideFlash { address: RAM, .. })
+    ));
+
+    let mut port = Recorder::new(device(), TraceWriter::new(Vec::new()));
+    let plan = plan_write(&mut port, &image, WriteOptions::default(), 115200).unwrap();
+    assert_eq!(plan.erase, plan::PlannedErase::None);
+    assert_eq!(
+        plan.write,
+        [plan::WriteBlock {
+            address: RAM,
+            len: 64
+        }]
+    );
+    assert_eq!(plan.verify, Some(plan::VerifyMethod::Off));
+    // the device is not attached, only Get ID is sent
+    let (_, trace) = port.into_parts();
+    let sent = read_trace(Cursor::new(trace.into_inner()))
+        .unwrap()
+        .into_iter()
+        .filter_map(|entry| match entry.event {
+            trace::Event::Sent(data) => Some(data),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sent, [encode_command(Command::GetId).to_vec()]);
+}
+
+#[test]
+fn wrong_chip_is_refused() {
+    let mut device = device();
+    let firmware = pattern(256);
+    device.load(FLASH, &firmware);
+    let image = MemoryImage::from_binary(FLASH, vec![0x55; 256]);
+    let config = FlashConfig {
+        expected_chip: Some(ExpectedChip::Pid(0x450)),
+        ..Default::default()
+    };
+    assert!(matches!(
+        prepare_flash(&mut device, &image, &config),
+        Err(BootloaderError::WrongChip { pid: 0x413, .. })
+    ));
+    assert_eq!(device.memory(FLASH, 256), firmware);
+
+    let config = FlashConfig {
+        expected_chip: Some("0x413".parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(prepare_flash(&mut device, &image, &config).is_ok());
+}
+
+#[test]
+fn expected_chip() {
+    assert_eq!("0x450".parse(), Ok(ExpectedChip::Pid(0x450)));
+    let f4: ExpectedChip = "STM32F4".parse().unwrap();
+    assert!(f4.matches(0x413));
+    assert!(f4.matches(0x419));
+    assert!(!f4.matches(0x450));
+    // unknown product IDs belong to no family
+    assert!(!f4.matches(0x123));
+    assert!("STM32X9".parse::<ExpectedChip>().is_err());
+}
@@ -0,0 +1,583 @@
+use stm32_firmware_loader::*;
+
+const FLASH: u32 = 0x0800_0000;
+
+#[test]
+fn insert_beyond_address_space() {
+    let mut image = MemoryImage::new();
+    image.insert(0xFFFF_FFFC, &[1, 2, 3, 4]).unwrap();
+    assert!(matches!(
+        MemoryImage::new().insert(0xFFFF_FFFC, &[0; 5]),
+        Err(BootloaderError::AddressOverflow {
+            address: 0xFFFF_FFFC,
+            len: 5,
+        })
+    ));
+}
+
+#[test]
+fn fill_gaps() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH + 4, &[1, 2]).unwrap();
+    image.insert(FLASH + 8, &[3]).unwrap();
+    image.entry = Some(FLASH + 5);
+    image.fill_gaps(0xFF);
+    assert_eq!(
+        image.segments(),
+        [Segment {
+            address: FLASH + 4,
+            data: vec![1, 2, 0xFF, 0xFF, 3],
+        }]
+    );
+    assert_eq!(image.entry, Some(FLASH + 5));
+
+    let mut empty = MemoryImage::new();
+    empty.fill_gaps(0xFF);
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn overlapping_data_is_rejected() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH + 8, &[0; 8]).unwrap();
+    assert!(matches!(
+        image.insert(FLASH + 12, &[0; 8]),
+        Err(BootloaderError::Overlap { address }) if address == FLASH + 12
+    ));
+    assert!(matches!(
+        image.insert(FLASH + 4, &[0; 8]),
+        Err(BootloaderError::Overlap { address }) if address == FLASH + 8
+    ));
+    assert_eq!(image.len(), 8);
+}
+
+#[test]
+fn adjacent_data_is_joined() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH + 8, &[3, 4]).unwrap();
+    image.insert(FLASH, &[1]).unwrap();
+    image.insert(FLASH + 4, &[2]).unwrap();
+    assert_eq!(image.segments().len(), 3);
+    image.insert(FLASH + 1, &[0; 3]).unwrap();
+    image.insert(FLASH + 5, &[0; 3]).unwrap();
+    assert_eq!(
+        image.segments(),
+        [Segment {
+            address: FLASH,
+            data: vec![1, 0, 0, 0, 2, 0, 0, 0, 3, 4],
+        }]
+    );
+}
+
+#[test]
+fn cropped() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH, &[1, 2, 3, 4]).unwrap();
+    image.insert(FLASH + 8, &[5, 6, 7, 8]).unwrap();
+    image.entry = Some(FLASH);
+
+    let cropped = image.cropped(FLASH + 2..FLASH + 10);
+    assert_eq!(
+        cropped.segments(),
+        [
+            Segment {
+                address: FLASH + 2,
+                data: vec![3, 4],
+            },
+            Segment {
+                address: FLASH + 8,
+                data: vec![5, 6],
+            },
+        ]
+    );
+    assert_eq!(cropped.entry, Some(FLASH));
+    assert!(image.cropped(FLASH + 4..FLASH + 8).is_empty());
+    assert_eq!(image.cropped(0..u32::MAX), image);
+}
+
+#[test]
+fn segments_sharing_a_programming_unit() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH, &[0; 20]).unwrap();
+    image.insert(FLASH + 24, &[0; 8]).unwrap();
+    image.check_granularity(8).unwrap();
+    assert!(matches!(
+        image.check_granularity(16),
+        Err(BootloaderError::PartialProgramUnit { address, granularity: 16 })
+            if address == FLASH + 16
+    ));
+
+    let mut image = MemoryImage::new();
+    image.insert(FLASH, &[1, 2]).unwrap();
+    image.insert(FLASH + 3, &[3]).unwrap();
+    image.insert(FLASH + 8, &[4]).unwrap();
+    assert_eq!(
+        image.joined(4, 0xFF).segments(),
+        [
+            Segment {
+                address: FLASH,
+                data: vec![1, 2, 0xFF, 3],
+            },
+            Segment {
+                address: FLASH + 8,
+                data: vec![4],
+            },
+        ]
+    );
+}
+
+// Intel HEX record with its checksum
+fn ihex_record(offset: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    bytes.push(sum.wrapping_neg());
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<String>();
+    format!(":{}\n", hex)
+}
+
+fn ihex(records: &[String]) -> Result<MemoryImage, BootloaderError> {
+    ihex::parse(&(records.concat() + ":00000001FF\n"))
+}
+
+fn invalid_file_reason(result: Result<MemoryImage, BootloaderError>) -> String {
+    match result {
+        Err(BootloaderError::InvalidFile { reason, .. }) => reason,
+        result => panic!("expected an invalid file, got {:?}", result),
+    }
+}
+
+#[test]
+fn ihex_addresses() {
+    let image = ihex(&[
+        // extended linear address 0x0800_0000
+        ihex_record(0, 0x04, &[0x08, 0x00]),
+        ihex_record(0x0000, 0x00, &[1, 2, 3, 4]),
+        ihex_record(0x0004, 0x00, &[5, 6]),
+        // extended segment address 0x1000 * 16
+        ihex_record(0, 0x02, &[0x10, 0x00]),
+        ihex_record(0x0010, 0x00, &[7]),
+        // start linear address
+        ihex_record(0, 0x05, &[0x08, 0x00, 0x01, 0x01]),
+    ])
+    .unwrap();
+    assert_eq!(
+        image.segments(),
+        [
+            Segment {
+                address: 0x0001_0010,
+                data: vec![7],
+            },
+            Segment {
+                address: FLASH,
+                data: vec![1, 2, 3, 4, 5, 6],
+            },
+        ]
+    );
+    assert_eq!(image.entry, Some(0x0800_0101));
+
+    // start segment address, CS:IP
+    let image = ihex(&[ihex_record(0, 0x03, &[0x12, 0x34, 0x00, 0x05])]).unwrap();
+    assert_eq!(image.entry, Some(0x12345));
+    assert!(image.is_empty());
+}
+
+#[test]
+fn ihex_errors() {
+    let mut record = ihex_record(0, 0x00, &[1, 2]);
+    record.replace_range(11..13, "00");
+    assert_eq!(
+        invalid_file_reason(ihex(&[record])),
+        "line 1: checksum mismatch"
+    );
+
+    let data = ihex_record(0x0100, 0x00, &[1, 2, 3, 4]);
+    let reason = invalid_file_reason(ihex(&[data, ihex_record(0x0102, 0x00, &[5])]));
+    assert!(reason.contains("0x00000102 overlaps"), "{}", reason);
+
+    let reason = invalid_file_reason(ihex(&[ihex_record(0, 0x04, &[0x08])]));
+    assert_eq!(reason, "line 1: invalid record length");
+    let reason = invalid_file_reason(ihex(&[ihex_record(0, 0x06, &[])]));
+    assert_eq!(reason, "line 1: unknown record type 0x06");
+    let reason = invalid_file_reason(ihex::parse(&ihex_record(0, 0x00, &[1])));
+    assert_eq!(reason, "line 1: missing end of file record");
+    let reason = invalid_file_reason(ihex::parse(":00000001FF\n:0100000001FE\n"));
+    assert_eq!(reason, "line 2: data after end of file record");
+    let reason = invalid_file_reason(ihex::parse("0100000001FE\n"));
+    assert_eq!(reason, "line 1: missing start code");
+}
+
+// S-record with its checksum, the address takes the size the record type uses
+fn srec_record(kind: u8, address: u32, data: &[u8]) -> String {
+    let address_len = match kind {
+        0 | 1 | 5 | 9 => 2,
+        2 | 6 | 8 => 3,
+        _ => 4,
+    };
+    let mut bytes = vec![(address_len + data.len() + 1) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    bytes.push(!sum);
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<String>();
+    format!("S{}{}\n", kind, hex)
+}
+
+#[test]
+fn srec_records() {
+    let text = [
+        srec_record(0, 0, b"HDR"),
+        srec_record(1, 0x1000, &[1, 2]),
+        srec_record(2, 0x01_0000, &[3]),
+        srec_record(3, FLASH, &[4, 5]),
+        srec_record(3, FLASH + 2, &[6]),
+        srec_record(5, 4, &[]),
+        srec_record(7, FLASH + 0x101, &[]),
+    ]
+    .concat();
+    let image = srec::parse(&text).unwrap();
+    assert_eq!(
+        image.segments(),
+        [
+            Segment {
+                address: 0x1000,
+                data: vec![1, 2],
+            },
+            Segment {
+                address: 0x01_0000,
+                data: vec![3],
+            },
+            Segment {
+                address: FLASH,
+                data: vec![4, 5, 6],
+            },
+        ]
+    );
+    assert_eq!(image.entry, Some(FLASH + 0x101));
+}
+
+#[test]
+fn srec_counts() {
+    let data = [srec_record(1, 0, &[1]), srec_record(1, 1, &[2])].concat();
+    srec::parse(&(data.clone() + &srec_record(5, 2, &[]))).unwrap();
+    // 24 bit count
+    srec::parse(&(data.clone() + &srec_record(6, 2, &[]))).unwrap();
+    let reason = invalid_file_reason(srec::parse(&(data.clone() + &srec_record(5, 3, &[]))));
+    assert_eq!(
+        reason,
+        "line 3: record count 3 does not match 2 data records"
+    );
+    let reason = invalid_file_reason(srec::parse(&(data + &srec_record(6, 1, &[]))));
+    assert_eq!(
+        reason,
+        "line 3: record count 1 does not match 2 data records"
+    );
+}
+
+#[test]
+fn srec_errors() {
+    let mut record = srec_record(1, 0x1000, &[1, 2]);
+    record.replace_range(12..14, "00");
+    assert_eq!(
+        invalid_file_reason(srec::parse(&record)),
+        "line 1: checksum mismatch"
+    );
+
+    let text = [
+        srec_record(1, 0x1000, &[1, 2]),
+        srec_record(1, 0x1001, &[3]),
+    ]
+    .concat();
+    let reason = invalid_file_reason(srec::parse(&text));
+    assert!(reason.contains("0x00001001 overlaps"), "{}", reason);
+
+    let text = [srec_record(9, 0, &[]), srec_record(1, 0, &[1])].concat();
+    assert_eq!(
+        invalid_file_reason(srec::parse(&text)),
+        "line 2: data after termination record"
+    );
+    assert_eq!(
+        invalid_file_reason(srec::parse(&srec_record(4, 0, &[]))),
+        "line 1: unknown record type S4"
+    );
+    assert_eq!(
+        invalid_file_reason(srec::parse("S1030000\n")),
+        "line 1: invalid record length"
+    );
+}
+
+// Program header of an ELF file: type, virtual and physical address, data
+struct ProgramHeader<'a>(u32, u32, u32, &'a [u8]);
+
+// ARM ELF32 executable with the program headers following the file header
+fn elf_file(entry: u32, headers: &[ProgramHeader]) -> Vec<u8> {
+    let mut data = b"\x7FELF\x01\x01\x01".to_vec();
+    data.resize(16, 0);
+    // executable for ARM, version 1
+    for half_word in [2u16, 40] {
+        data.extend_from_slice(&half_word.to_le_bytes());
+    }
+    for word in [1, entry, 52, 0, 0] {
+        data.extend_from_slice(&u32::to_le_bytes(word));
+    }
+    // header sizes and numbers
+    for half_word in [52u16, 32, headers.len() as u16, 40, 0, 0] {
+        data.extend_from_slice(&half_word.to_le_bytes());
+    }
+    let mut offset = 52 + 32 * headers.len();
+    for ProgramHeader(kind, vaddr, paddr, contents) in headers {
+        let len = contents.len() as u32;
+        for word in [*kind, offset as u32, *vaddr, *paddr, len, len + 16, 7, 4] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        offset += contents.len();
+    }
+    for ProgramHeader(_, _, _, contents) in headers {
+        data.extend_from_slice(contents);
+    }
+    data
+}
+
+#[test]
+fn elf_load_addresses() {
+    let data = elf_file(
+        FLASH + 0x41,
+        &[
+            ProgramHeader(1, FLASH, FLASH, &[1, 2, 3, 4]),
+            // .data runs in RAM and is loaded behind the code
+            ProgramHeader(1, 0x2000_0000, FLASH + 4, &[5, 6]),
+            // RAM functions without a copy in flash
+            ProgramHeader(1, 0x2000_0100, 0x2000_0100, &[7]),
+            // CCM data
+            ProgramHeader(1, 0x1000_0000, 0x1000_0000, &[9]),
+            // .bss
+            ProgramHeader(1, 0x2000_0200, 0x2000_0200, &[]),
+            // not loaded
+            ProgramHeader(4, 0, 0, &[8]),
+        ],
+    );
+    let image = elf::parse(&data).unwrap();
+    // segments outside of flash are kept until the device is known
+    assert_eq!(
+        image.segments(),
+        [
+            Segment {
+                address: FLASH,
+                data: vec![1, 2, 3, 4, 5, 6],
+            },
+            Segment {
+                address: 0x1000_0000,
+                data: vec![9],
+            },
+            Segment {
+                address: 0x2000_0100,
+                data: vec![7],
+            },
+        ]
+    );
+    assert_eq!(image.entry, Some(FLASH + 0x41));
+
+    let mut flash = image.clone();
+    flash.crop_to_flash(device::lookup(0x413));
+    assert_eq!(
+        flash.segments(),
+        [Segment {
+            address: FLASH,
+            data: vec![1, 2, 3, 4, 5, 6],
+        }]
+    );
+    assert_eq!(flash.entry, Some(FLASH + 0x41));
+    // without a device only the code region is kept
+    let mut code = image.clone();
+    code.crop_to_flash(None);
+    assert_eq!(code.segments().len(), 2);
+
+    // executables without extension are detected by the magic number
+    let path = std::env::temp_dir().join(format!("elf-test-{}", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    let loaded = image::load_file(&path, 0);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), image);
+}
+
+#[test]
+fn elf_errors() {
+    let reason = |data: &[u8]| invalid_file_reason(elf::parse(data));
+    assert_eq!(reason(&[0; 64]), "not an ELF file");
+
+    let mut data = elf_file(0, &[]);
+    data[18] = 3;
+    assert_eq!(reason(&data), "unsupported machine 3");
+
+    let mut data = elf_file(0, &[]);
+    data[4] = 2;
+    assert_eq!(reason(&data), "not a 32 bit little endian ELF file");
+
+    let mut data = elf_file(0, &[ProgramHeader(1, FLASH, FLASH, &[1, 2, 3, 4])]);
+    data.truncate(data.len() - 1);
+    assert_eq!(reason(&data), "segment exceeds file");
+
+    let data = elf_file(
+        0,
+        &[
+            ProgramHeader(1, FLASH, FLASH, &[1, 2, 3, 4]),
+            ProgramHeader(1, 0x2000_0000, FLASH + 2, &[5]),
+        ],
+    );
+    assert!(reason(&data).contains("0x08000002 overlaps"));
+}
+
+// Offset of the first element in a DfuSe file
+const PREFIX_AND_TARGET: usize = 11 + 274;
+
+// Address and data of a DfuSe image element
+type Element<'a> = (u32, &'a [u8]);
+
+// DfuSe file of targets given by their alternate setting, name and elements
+fn dfuse_file(targets: &[(u8, &str, &[Element])]) -> Vec<u8> {
+    let mut data = b"DfuSe\x01".to_vec();
+    // image size, filled in below
+    data.extend_from_slice(&[0; 4]);
+    data.push(targets.len() as u8);
+    for (alternate_setting, name, elements) in targets {
+        data.extend_from_slice(b"Target");
+        data.push(*alternate_setting);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        let mut target_name = name.as_bytes().to_vec();
+        target_name.resize(255, 0);
+        data.extend_from_slice(&target_name);
+        let size: usize = elements.iter().map(|(_, element)| 8 + element.len()).sum();
+        data.extend_from_slice(&(size as u32).to_le_bytes());
+        data.extend_from_slice(&(elements.len() as u32).to_le_bytes());
+        for (address, element) in elements.iter() {
+            data.extend_from_slice(&address.to_le_bytes());
+            data.extend_from_slice(&(element.len() as u32).to_le_bytes());
+            data.extend_from_slice(element);
+        }
+    }
+    let image_size = data.len() as u32;
+    data[6..10].copy_from_slice(&image_size.to_le_bytes());
+    // device, product and vendor ID, DFU version 1.1a
+    data.extend_from_slice(&[0xFF, 0xFF, 0x11, 0xDF, 0x83, 0x04, 0x1A, 0x01]);
+    data.extend_from_slice(b"UFD\x10");
+    let crc = crc::dfu_crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+    data
+}
+
+#[test]
+fn dfuse_targets() {
+    let data = dfuse_file(&[
+        (
+            0,
+            "Internal Flash",
+            &[(FLASH, &[1, 2, 3, 4]), (FLASH + 0x4000, &[5])],
+        ),
+        (0, "Internal Flash", &[(FLASH + 4, &[6, 7])]),
+    ]);
+    let image = dfuse::parse(&data).unwrap();
+    assert_eq!(
+        image.segments(),
+        [
+            Segment {
+                address: FLASH,
+                data: vec![1, 2, 3, 4, 6, 7],
+            },
+            Segment {
+                address: FLASH + 0x4000,
+                data: vec![5],
+            },
+        ]
+    );
+    assert_eq!(image.entry, None);
+}
+
+#[test]
+fn dfuse_suffix_crc() {
+    // the CRC of the DFU suffix is not inverted at the end
+    assert_eq!(crc::dfu_crc32(b"123456789"), !0xCBF4_3926);
+
+    let mut data = dfuse_file(&[(0, "Internal Flash", &[(FLASH, &[1, 2, 3, 4])])]);
+    data[PREFIX_AND_TARGET] ^= 0xFF;
+    let reason = invalid_file_reason(dfuse::parse(&data));
+    assert!(reason.starts_with("CRC mismatch"), "{}", reason);
+
+    let mut data = dfuse_file(&[]);
+    let len = data.len();
+    data[len - 8] = b'X';
+    assert_eq!(
+        invalid_file_reason(dfuse::parse(&data)),
+        "missing DFU suffix"
+    );
+}
+
+#[test]
+fn dfuse_other_memories_are_rejected() {
+    let data = dfuse_file(&[
+        (0, "Internal Flash", &[(FLASH, &[1, 2, 3, 4])]),
+        (1, "Option Bytes", &[(0x1FFF_C000, &[0xAA, 0xF8])]),
+    ]);
+    assert_eq!(
+        invalid_file_reason(dfuse::parse(&data)),
+        "target 1 \"Option Bytes\" is not the internal flash"
+    );
+}
+
+// Vector table with the initial stack pointer and the reset vector
+fn vector_table(sp: u32, reset: u32) -> Vec<u8> {
+    let mut data = sp.to_le_bytes().to_vec();
+    data.extend_from_slice(&reset.to_le_bytes());
+    data.resize(64, 0);
+    data
+}
+
+#[test]
+fn vector_table_check() {
+    let f4 = device::lookup(0x413);
+    let image = MemoryImage::from_binary(FLASH, vector_table(0x2002_0000, FLASH + 0x21));
+    image.check_vector_table(f4).unwrap();
+
+    let image = MemoryImage::from_binary(FLASH, vector_table(0x2010_0000, FLASH + 0x21));
+    image.check_vector_table(None).unwrap();
+    assert!(matches!(
+        image.check_vector_table(f4),
+        Err(BootloaderError::InvalidVectorTable { address: FLASH, .. })
+    ));
+
+    // not thumb code
+    let image = MemoryImage::from_binary(FLASH, vector_table(0x2002_0000, FLASH + 0x20));
+    assert!(image.check_vector_table(f4).is_err());
+    // outside of the image
+    let image = MemoryImage::from_binary(FLASH, vector_table(0x2002_0000, FLASH + 0x1001));
+    assert!(image.check_vector_table(f4).is_err());
+    assert!(MemoryImage::from_binary(FLASH, vec![0; 4])
+        .check_vector_table(None)
+        .is_err());
+}
+
+#[test]
+fn vector_check_levels() {
+    let image = MemoryImage::from_binary(FLASH, vector_table(0x2002_0000, FLASH + 0x20));
+    VectorCheck::Off.check(&image, None).unwrap();
+    VectorCheck::Warn.check(&image, None).unwrap();
+    assert!(VectorCheck::Abort.check(&image, None).is_err());
+    assert_eq!("warn".parse(), Ok(VectorCheck::Warn));
+    assert!("strict".parse::<VectorCheck>().is_err());
+}
+
+#[test]
+fn go_address() {
+    let mut image = MemoryImage::from_binary(FLASH + 0x4000, vector_table(0x2002_0000, 0));
+    image.insert(FLASH, &[0; 16]).unwrap();
+    assert_eq!(image.go_address(), None);
+    image.entry = Some(FLASH + 0x4021);
+    assert_eq!(image.go_address(), Some(FLASH + 0x4000));
+}
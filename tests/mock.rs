@@ -0,0 +1,511 @@
+mod common;
+
+use stm32_firmware_loader::mock::{Fault, MockConfig, MockDevice};
+use stm32_firmware_loader::*;
+
+use common::{device, device_with, pattern};
+
+const FLASH: u32 = 0x0800_0000;
+const RAM: u32 = 0x2000_0000;
+
+fn with_checksum() -> MockConfig {
+    let mut config = MockConfig {
+        version: 0x33,
+        ..Default::default()
+    };
+    config.commands.push(Command::GetChecksum.opcode());
+    config
+}
+
+#[test]
+fn hello_requires_sync() {
+    let mut device = MockDevice::new(MockConfig::default());
+    assert!(matches!(
+        get_id(&mut device),
+        Err(BootloaderError::Timeout {
+            command: Command::GetId,
+            stage: Stage::Command,
+            ..
+        })
+    ));
+    hello(&mut device).unwrap();
+    assert_eq!(get_id(&mut device).unwrap(), 0x413);
+}
+
+#[test]
+fn get_commands() {
+    let mut device = device();
+    let (version, commands) = get(&mut device).unwrap();
+    assert_eq!(version, 0x31);
+    assert_eq!(commands, mock::DEFAULT_COMMANDS);
+    assert_eq!(get_version(&mut device).unwrap(), 0x31);
+    assert_eq!(get_id(&mut device).unwrap(), 0x413);
+}
+
+#[test]
+fn configurable_pid() {
+    let config = MockConfig {
+        pid: 0x123,
+        ..Default::default()
+    };
+    let mut bootloader = Bootloader::new(MockDevice::new(config)).unwrap();
+    assert!(matches!(
+        bootloader.device(),
+        Err(BootloaderError::UnknownDevice(0x123))
+    ));
+}
+
+#[test]
+fn unsupported_command() {
+    let mut device = device();
+    assert!(matches!(
+        get_checksum(&mut device, FLASH, 4),
+        Err(BootloaderError::Nack {
+            command: Command::GetChecksum,
+            stage: Stage::Command,
+            ..
+        })
+    ));
+
+    // the session does not send it at all
+    let mut bootloader = Bootloader::attach(device).unwrap();
+    assert!(matches!(
+        bootloader.get_checksum(FLASH, 4),
+        Err(BootloaderError::Unsupported(Command::GetChecksum))
+    ));
+}
+
+#[test]
+fn read_write_memory() {
+    let mut device = device();
+    let data = pattern(256);
+    write_memory_block(&mut device, FLASH, &data).unwrap();
+    assert_eq!(device.memory(FLASH, 256), &data[..]);
+
+    let mut read = [0; 256];
+    read_memory(&mut device, FLASH, &mut read).unwrap();
+    assert_eq!(read, &data[..]);
+    assert_eq!(
+        read_memory_vec(&mut device, FLASH + 16, 8).unwrap(),
+        &data[16..24]
+    );
+
+    write_memory_block(&mut device, RAM + 0x4000, &data[..8]).unwrap();
+    assert_eq!(device.memory(RAM + 0x4000, 8), &data[..8]);
+}
+
+#[test]
+fn read_outside_memory() {
+    let mut device = device();
+    let mut read = [0; 4];
+    assert!(matches!(
+        read_memory(&mut device, 0x9000_0000, &mut read),
+        Err(BootloaderError::Nack {
+            stage: Stage::Address,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn write_requires_erased_flash() {
+    let mut device = device();
+    write_memory_block(&mut device, FLASH, &[1, 2, 3, 4]).unwrap();
+    // the same data again is fine
+    write_memory_block(&mut device, FLASH, &[1, 2, 3, 4]).unwrap();
+    assert!(matches!(
+        write_memory_block(&mut device, FLASH, &[5, 6, 7, 8]),
+        Err(BootloaderError::Nack {
+            command: Command::WriteMemory,
+            stage: Stage::Data,
+            ..
+        })
+    ));
+    assert_eq!(device.memory(FLASH, 4), &[1, 2, 3, 4]);
+}
+
+#[test]
+fn write_memory_pads_unaligned_data() {
+    let mut device = device();
+    let data = pattern(1000);
+    write_memory(&mut device, FLASH + 2, &data, WriteOptions::default()).unwrap();
+    assert_eq!(device.memory(FLASH, 2), &[0xFF, 0xFF]);
+    assert_eq!(device.memory(FLASH + 2, 1000), &data[..]);
+    assert_eq!(device.memory(FLASH + 1002, 2), &[0xFF, 0xFF]);
+    verify_memory(&mut device, FLASH + 2, &data, WriteOptions::default()).unwrap();
+}
+
+#[test]
+fn segments_sharing_a_word() {
+    for config in [MockConfig::default(), with_checksum()] {
+        let mut device = device_with(config);
+        let mut image = MemoryImage::new();
+        image.insert(FLASH, &[1, 2]).unwrap();
+        image.insert(FLASH + 3, &pattern(5)).unwrap();
+        write_image(&mut device, &image, WriteOptions::default()).unwrap();
+        let mut expected = vec![1, 2, 0xFF];
+        expected.extend_from_slice(&pattern(5));
+        assert_eq!(device.memory(FLASH, 8), expected);
+        // the padding after each segment holds the data of the next one
+        verify_image(&mut device, &image, WriteOptions::default()).unwrap();
+
+        device.load(FLASH + 4, &[0]);
+        assert!(matches!(
+            verify_image(&mut device, &image, WriteOptions::default()),
+            Err(BootloaderError::Mismatch { address, actual: 0, .. }) if address == FLASH + 4
+        ));
+    }
+}
+
+#[test]
+fn verify_detects_mismatch() {
+    for config in [MockConfig::default(), with_checksum()] {
+        let mut device = device_with(config);
+        let data = pattern(0x2000);
+        write_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+        verify_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+
+        device.load(FLASH + 0x1234, &[0]);
+        assert!(matches!(
+            verify_memory(&mut device, FLASH, &data, WriteOptions::default()),
+            Err(BootloaderError::Mismatch {
+                address: 0x0800_1234,
+                actual: 0,
+                ..
+            })
+        ));
+    }
+}
+
+#[test]
+fn verify_rejects_wrong_checksum() {
+    let mut device = device_with(with_checksum());
+    let data = pattern(0x800);
+    write_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+    device.inject(Fault::WrongChecksum);
+    // the data reads back correctly, but the checksum can not be trusted
+    assert!(matches!(
+        verify_memory(&mut device, FLASH, &data, WriteOptions::default()),
+        Err(BootloaderError::ChecksumMismatch {
+            address: FLASH,
+            len: 0x800,
+            ..
+        })
+    ));
+    verify_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+}
+
+#[test]
+fn verify_skips_erased_blocks() {
+    let mut device = device();
+    let mut data = pattern(0x400);
+    data[0x100..0x200].fill(0xFF);
+    let options = WriteOptions {
+        skip: SkipPolicy::Erased,
+        ..Default::default()
+    };
+    write_memory(&mut device, FLASH, &data, options).unwrap();
+    // a skipped block is not compared
+    device.load(FLASH + 0x100, &[0]);
+    verify_memory(&mut device, FLASH, &data, options).unwrap();
+    assert!(verify_memory(&mut device, FLASH, &data, WriteOptions::default()).is_err());
+}
+
+#[test]
+fn checksum() {
+    let mut device = device_with(with_checksum());
+    let data = pattern(0x800);
+    write_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+    assert_eq!(
+        get_checksum(&mut device, FLASH, 0x800).unwrap(),
+        crc::stm32_crc32(&data)
+    );
+    assert!(matches!(
+        get_checksum(&mut device, FLASH + 2, 4),
+        Err(BootloaderError::Unaligned { .. })
+    ));
+}
+
+#[test]
+fn erase() {
+    let mut config = MockConfig::default();
+    config.commands.retain(|&opcode| opcode != 0x44);
+    config.commands.push(Command::Erase.opcode());
+    let mut device = device_with(config);
+    let data = pattern(256);
+    write_memory_block(&mut device, FLASH, &data).unwrap();
+    write_memory_block(&mut device, FLASH + 0x4000, &data).unwrap();
+
+    erase_memory(&mut device, &[1]).unwrap();
+    assert_eq!(device.memory(FLASH, 256), &data[..]);
+    assert!(device
+        .memory(FLASH + 0x4000, 256)
+        .iter()
+        .all(|&b| b == 0xFF));
+
+    erase_memory_global(&mut device).unwrap();
+    assert!(device.memory(FLASH, 256).iter().all(|&b| b == 0xFF));
+
+    assert!(matches!(
+        erase_memory(&mut device, &[12]),
+        Err(BootloaderError::Nack {
+            command: Command::Erase,
+            stage: Stage::Data,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn extended_erase_pages() {
+    let mut device = device();
+    let data = pattern(256);
+    write_memory_block(&mut device, FLASH, &data).unwrap();
+    write_memory_block(&mut device, FLASH + 0x4000, &data).unwrap();
+    write_memory_block(&mut device, FLASH + 0x8000, &data).unwrap();
+
+    extended_erase(&mut device, &[0, 2]).unwrap();
+    assert!(device.memory(FLASH, 256).iter().all(|&b| b == 0xFF));
+    assert_eq!(device.memory(FLASH + 0x4000, 256), &data[..]);
+    assert!(device
+        .memory(FLASH + 0x8000, 256)
+        .iter()
+        .all(|&b| b == 0xFF));
+
+    extended_erase_special(&mut device, SpecialEraseType::MassErase).unwrap();
+    assert!(device
+        .memory(FLASH + 0x4000, 256)
+        .iter()
+        .all(|&b| b == 0xFF));
+}
+
+#[test]
+fn erase_banks() {
+    let mut device = device();
+    let data = pattern(256);
+    // sector 0 in the first half, sector 11 in the second half
+    write_memory_block(&mut device, FLASH, &data).unwrap();
+    write_memory_block(&mut device, 0x080E_0000, &data).unwrap();
+
+    extended_erase_special(&mut device, SpecialEraseType::Bank2Erase).unwrap();
+    assert_eq!(device.memory(FLASH, 256), &data[..]);
+    assert!(device.memory(0x080E_0000, 256).iter().all(|&b| b == 0xFF));
+
+    extended_erase_special(&mut device, SpecialEraseType::Bank1Erase).unwrap();
+    assert!(device.memory(FLASH, 256).iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn write_protection() {
+    let mut device = device();
+    write_protect(&mut device, &[1]).unwrap();
+    assert!(device.is_write_protected(1));
+    assert_eq!(device.resets(), 1);
+
+    assert!(matches!(
+        extended_erase(&mut device, &[1]),
+        Err(BootloaderError::Nack { .. })
+    ));
+    assert!(matches!(
+        write_memory_block(&mut device, FLASH + 0x4000, &[0; 4]),
+        Err(BootloaderError::Nack { .. })
+    ));
+    // other sectors are not affected
+    write_memory_block(&mut device, FLASH, &[0; 4]).unwrap();
+
+    write_unprotect(&mut device).unwrap();
+    assert!(!device.is_write_protected(1));
+    write_memory_block(&mut device, FLASH + 0x4000, &[0; 4]).unwrap();
+}
+
+#[test]
+fn readout_protection() {
+    let mut device = device();
+    write_memory_block(&mut device, FLASH, &[1, 2, 3, 4]).unwrap();
+    readout_protect(&mut device).unwrap();
+    assert!(device.is_readout_protected());
+
+    let mut read = [0; 4];
+    assert!(matches!(
+        read_memory(&mut device, FLASH, &mut read),
+        Err(BootloaderError::Nack {
+            command: Command::ReadMemory,
+            stage: Stage::Command,
+            ..
+        })
+    ));
+    // identification still works
+    assert_eq!(get_id(&mut device).unwrap(), 0x413);
+
+    // removing the protection erases the flash memory
+    readout_unprotect(&mut device).unwrap();
+    assert!(!device.is_readout_protected());
+    read_memory(&mut device, FLASH, &mut read).unwrap();
+    assert_eq!(read, [0xFF; 4]);
+    assert_eq!(device.resets(), 2);
+}
+
+#[test]
+fn go_starts_application() {
+    let mut device = device();
+    go(&mut device, FLASH).unwrap();
+    assert_eq!(device.jumped_to(), Some(FLASH));
+    // the bootloader is gone
+    assert!(get_id(&mut device).is_err());
+}
+
+#[test]
+fn special_commands() {
+    let mut config = MockConfig {
+        version: 0x33,
+        ..Default::default()
+    };
+    config.commands.extend([0x50, 0x51]);
+    let mut device = device_with(config);
+
+    let response = special_command(&mut device, 0x0102, &[1, 2, 3]).unwrap();
+    assert_eq!(response.data, [1, 2, 3]);
+    assert!(response.status.is_empty());
+
+    let response = extended_special_command(&mut device, 0x0102, &[1], &[2; 300]).unwrap();
+    assert!(response.data.is_empty());
+    assert!(response.status.is_empty());
+}
+
+#[test]
+fn bootloader_ram_is_not_written() {
+    let mut bootloader = Bootloader::attach(device()).unwrap();
+    // the bootloader of the STM32F405/407 uses the first 12 KiB of RAM
+    assert!(matches!(
+        bootloader.write_memory(RAM + 0x2FF0, &[0; 32], WriteOptions::default()),
+        Err(BootloaderError::BootloaderRam {
+            address: 0x2000_2FF0
+        })
+    ));
+    bootloader
+        .write_memory(RAM + 0x3000, &[0x12; 32], WriteOptions::default())
+        .unwrap();
+    let device = bootloader.into_inner();
+    assert_eq!(device.memory(RAM + 0x2FF0, 16), [0; 16]);
+    assert_eq!(device.memory(RAM + 0x3000, 32), [0x12; 32]);
+}
+
+#[test]
+fn write_and_verify_image() {
+    let mut image = MemoryImage::new();
+    image.insert(FLASH, &pattern(0x300)).unwrap();
+    image.insert(FLASH + 0x4002, &pattern(0x11)).unwrap();
+
+    let mut bootloader = Bootloader::new(MockDevice::new(MockConfig::default())).unwrap();
+    bootloader
+        .erase_for_image(EraseStrategy::CoveredSectorsOnly, &image)
+        .unwrap();
+    write_image(bootloader.port(), &image, WriteOptions::default()).unwrap();
+    verify_image(bootloader.port(), &image, WriteOptions::default()).unwrap();
+
+    let diff = bootloader
+        .diff_sectors(&image, WriteOptions::default())
+        .unwrap();
+    assert!(diff.changed.is_empty());
+    assert_eq!(diff.unchanged.len(), 2);
+
+    let mut changed = MemoryImage::new();
+    changed.insert(FLASH + 0x4000, &[0; 4]).unwrap();
+    let diff = bootloader
+        .diff_sectors(&changed, WriteOptions::default())
+        .unwrap();
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].index, 1);
+}
+
+#[test]
+fn fault_nack() {
+    let mut device = device();
+    device.inject(Fault::Nack(Stage::Command));
+    assert!(matches!(
+        get_id(&mut device),
+        Err(BootloaderError::Nack {
+            command: Command::GetId,
+            stage: Stage::Command,
+            ..
+        })
+    ));
+    // only the next ACK is replaced
+    assert_eq!(get_id(&mut device).unwrap(), 0x413);
+
+    device.inject(Fault::Nack(Stage::Data));
+    assert!(matches!(
+        write_memory_block(&mut device, FLASH, &[0; 4]),
+        Err(BootloaderError::Nack {
+            stage: Stage::Data,
+            address: Some(FLASH),
+            ..
+        })
+    ));
+    assert_eq!(device.memory(FLASH, 4), &[0xFF; 4]);
+}
+
+#[test]
+fn fault_garbage() {
+    let mut device = device();
+    device.inject(Fault::Garbage(Stage::Address, 0x42));
+    let mut read = [0; 4];
+    assert!(matches!(
+        read_memory(&mut device, FLASH, &mut read),
+        Err(BootloaderError::UnexpectedByte {
+            stage: Stage::Address,
+            byte: 0x42,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn fault_timeout() {
+    let mut device = device();
+    device.inject(Fault::Timeout(Stage::Response));
+    assert!(matches!(
+        get(&mut device),
+        Err(BootloaderError::Timeout {
+            command: Command::Get,
+            stage: Stage::Response,
+            ..
+        })
+    ));
+    assert!(get(&mut device).is_ok());
+}
+
+#[test]
+fn fault_dropped_bytes() {
+    let mut device = device();
+    // a byte of the read data is lost
+    device.inject(Fault::DropOutput(10));
+    let mut read = [0; 16];
+    assert!(matches!(
+        read_memory(&mut device, FLASH, &mut read),
+        Err(BootloaderError::Timeout {
+            stage: Stage::Response,
+            ..
+        })
+    ));
+
+    // a byte of the address is lost, the device keeps waiting
+    device.inject(Fault::DropInput(3));
+    assert!(matches!(
+        read_memory(&mut device, FLASH, &mut read),
+        Err(BootloaderError::Timeout {
+            stage: Stage::Address,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn verify_retries_after_timeout() {
+    let mut device = device();
+    let data = pattern(0x200);
+    write_memory(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+    device.inject(Fault::Timeout(Stage::Length));
+    device.inject(Fault::Nack(Stage::Address));
+    verify_memory_readback(&mut device, FLASH, &data, WriteOptions::default()).unwrap();
+}
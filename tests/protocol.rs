@@ -0,0 +1,221 @@
+mod common;
+
+use std::io::Cursor;
+
+use stm32_firmware_loader::protocol::*;
+use stm32_firmware_loader::trace::{self, read_trace, Recorder, TraceWriter};
+use stm32_firmware_loader::*;
+
+use common::device;
+
+const FLASH: u32 = 0x0800_0000;
+
+// Runs the exchange, answering each receive with the next bytes of the answer
+// in pieces of at most chunk bytes. Returns the sent frames and the response.
+fn run(
+    request: Request,
+    answer: &[u8],
+    chunk: usize,
+) -> Result<(Vec<Vec<u8>>, Response), BootloaderError> {
+    let mut exchange = Exchange::new(request)?;
+    let mut sent = Vec::new();
+    let mut answer = answer;
+    loop {
+        match exchange.poll() {
+            Event::Send(data) => sent.push(data),
+            Event::Receive(len) => {
+                assert!(!answer.is_empty(), "exchange waits for more data");
+                let len = len.min(chunk).min(answer.len());
+                let used = exchange.receive(&answer[..len])?;
+                assert_eq!(used, len);
+                answer = &answer[len..];
+            }
+            Event::Done(response) => {
+                assert!(answer.is_empty());
+                return Ok((sent, response));
+            }
+        }
+    }
+}
+
+#[test]
+fn encoders() {
+    assert_eq!(encode_command(Command::ReadMemory), [0x11, 0xEE]);
+    assert_eq!(encode_word(0x0800_1234), [0x08, 0x00, 0x12, 0x34, 0x2E]);
+    assert_eq!(encode_read_length(256), [0xFF, 0x00]);
+    assert_eq!(encode_block(&[1, 2, 3, 4]), [3, 1, 2, 3, 4, 7]);
+    assert_eq!(encode_pages(&[0, 1]), [0, 1, 0, 0, 0, 1, 0]);
+    assert_eq!(
+        encode_special_erase(SpecialEraseType::Bank1Erase),
+        [0xFF, 0xFE, 0x01]
+    );
+    assert_eq!(
+        encode_special(Some(0x0102), &[0xAA]),
+        [0x01, 0x02, 0x00, 0x01, 0xAA, 0xA8]
+    );
+}
+
+// AN3155 sends the number of pages minus one, a single page is sent as 0
+#[test]
+fn extended_erase_page_count() {
+    assert_eq!(encode_pages(&[5]), [0x00, 0x00, 0x00, 0x05, 0x05]);
+
+    let mut port = Recorder::new(device(), TraceWriter::new(Vec::new()));
+    extended_erase(&mut port, &[1, 2, 3]).unwrap();
+    let (_, trace) = port.into_parts();
+    let sent = read_trace(Cursor::new(trace.into_inner()))
+        .unwrap()
+        .into_iter()
+        .filter_map(|entry| match entry.event {
+            trace::Event::Sent(data) => Some(data),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sent,
+        [
+            vec![0x44, 0xBB],
+            vec![0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x02]
+        ]
+    );
+}
+
+#[test]
+fn read_memory_byte_by_byte() {
+    let request = Request::ReadMemory {
+        address: FLASH,
+        len: 4,
+    };
+    let answer = [ACK, ACK, ACK, 1, 2, 3, 4];
+    let (sent, response) = run(request, &answer, 1).unwrap();
+    assert_eq!(
+        sent,
+        [
+            vec![0x11, 0xEE],
+            vec![0x08, 0x00, 0x00, 0x00, 0x08],
+            vec![0x03, 0xFC]
+        ]
+    );
+    assert_eq!(response, Response::Data(vec![1, 2, 3, 4]));
+}
+
+#[test]
+fn responses() {
+    let (_, response) = run(Request::Get, &[ACK, 2, 0x31, 0x00, 0x02, ACK], 16).unwrap();
+    assert_eq!(
+        response,
+        Response::Get {
+            version: 0x31,
+            commands: vec![0x00, 0x02]
+        }
+    );
+
+    let (_, response) = run(Request::GetId, &[ACK, 1, 0x04, 0x13, ACK], 16).unwrap();
+    assert_eq!(response, Response::Id(0x413));
+
+    let request = Request::Special {
+        opcode: 0x0102,
+        data: vec![],
+    };
+    let (_, response) = run(request, &[ACK, ACK, 0, 1, 0xAB, 0, 0, ACK], 3).unwrap();
+    assert_eq!(
+        response,
+        Response::Special(SpecialResponse {
+            data: vec![0xAB],
+            status: vec![],
+        })
+    );
+
+    let request = Request::GetChecksum {
+        address: FLASH,
+        len: 4,
+    };
+    let answer = [ACK, ACK, ACK, 0x12, 0x34, 0x56, 0x78, 0x08];
+    let (_, response) = run(request, &answer, 16).unwrap();
+    assert_eq!(response, Response::Checksum(0x1234_5678));
+}
+
+#[test]
+fn receive_stops_at_frame_to_send() {
+    let mut exchange = Exchange::new(Request::Go { address: FLASH }).unwrap();
+    assert_eq!(exchange.poll(), Event::Send(vec![0x21, 0xDE]));
+    assert_eq!(exchange.poll(), Event::Receive(1));
+    // the second ACK is only expected after the address has been sent
+    assert_eq!(exchange.receive(&[ACK, ACK]).unwrap(), 1);
+    assert!(matches!(exchange.poll(), Event::Send(_)));
+    assert_eq!(exchange.receive(&[ACK]).unwrap(), 1);
+    assert_eq!(exchange.poll(), Event::Done(Response::Ack));
+}
+
+#[test]
+fn errors() {
+    let request = Request::WriteMemory {
+        address: FLASH,
+        data: vec![0; 4],
+    };
+    assert!(matches!(
+        run(request, &[ACK, NACK], 1),
+        Err(BootloaderError::Nack {
+            command: Command::WriteMemory,
+            stage: Stage::Address,
+            address: Some(FLASH),
+        })
+    ));
+
+    assert!(matches!(
+        run(Request::Hello, &[0x55], 1),
+        Err(BootloaderError::UnexpectedByte {
+            command: Command::Hello,
+            stage: Stage::Command,
+            byte: 0x55,
+            ..
+        })
+    ));
+
+    let request = Request::GetChecksum {
+        address: FLASH,
+        len: 4,
+    };
+    let answer = [ACK, ACK, ACK, 0x12, 0x34, 0x56, 0x78, 0x00];
+    assert!(matches!(
+        run(request, &answer, 16),
+        Err(BootloaderError::UnexpectedByte {
+            command: Command::GetChecksum,
+            stage: Stage::Response,
+            byte: 0x00,
+            ..
+        })
+    ));
+
+    let mut exchange = Exchange::new(Request::ReadoutUnprotect).unwrap();
+    exchange.poll();
+    exchange.receive(&[ACK]).unwrap();
+    assert_eq!(exchange.stage(), Stage::Completion);
+}
+
+#[test]
+fn validation() {
+    let request = Request::WriteMemory {
+        address: FLASH + 2,
+        data: vec![0; 4],
+    };
+    assert!(matches!(
+        Exchange::new(request),
+        Err(BootloaderError::Unaligned {
+            command: Command::WriteMemory,
+            address: 0x0800_0002,
+        })
+    ));
+    let request = Request::ReadMemory {
+        address: FLASH,
+        len: 257,
+    };
+    assert!(matches!(
+        Exchange::new(request),
+        Err(BootloaderError::InvalidLength {
+            command: Command::ReadMemory,
+            len: 257,
+        })
+    ));
+    assert!(Exchange::new(Request::Erase { sectors: vec![] }).is_err());
+}
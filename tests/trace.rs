@@ -0,0 +1,132 @@
+mod common;
+
+use std::io::Cursor;
+
+use stm32_firmware_loader::mock::{Fault, MockConfig, MockDevice};
+use stm32_firmware_loader::trace::{read_trace, Event, Recorder, Replay, TraceEntry, TraceWriter};
+use stm32_firmware_loader::*;
+
+use common::device;
+
+const FLASH: u32 = 0x0800_0000;
+
+fn record<F, R>(device: MockDevice, f: F) -> (R, Vec<TraceEntry>)
+where
+    F: FnOnce(&mut Recorder<MockDevice, Vec<u8>>) -> R,
+{
+    let mut port = Recorder::new(device, TraceWriter::new(Vec::new()));
+    let res = f(&mut port);
+    let (_, trace) = port.into_parts();
+    let entries = read_trace(Cursor::new(trace.into_inner())).unwrap();
+    (res, entries)
+}
+
+#[test]
+fn entry_format() {
+    let entry = TraceEntry {
+        time: std::time::Duration::from_micros(1_000_012),
+        event: Event::Received(vec![0x79, 0x01, 0x04]),
+    };
+    assert_eq!(entry.to_string(), "1.000012 < 79 01 04");
+    assert_eq!(entry.to_string().parse::<TraceEntry>().unwrap(), entry);
+    assert_eq!(
+        "0.5 ! timeout".parse::<TraceEntry>().unwrap().event,
+        Event::Timeout
+    );
+    assert!("0.5 ? 00".parse::<TraceEntry>().is_err());
+}
+
+#[test]
+fn record_and_replay() {
+    let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+    let (res, entries) = record(device(), |port| {
+        write_memory(port, FLASH, &data, WriteOptions::default())?;
+        verify_memory(port, FLASH, &data, WriteOptions::default())
+    });
+    res.unwrap();
+    assert_eq!(entries[0].event, Event::Sent(vec![0x31, 0xCE]));
+    assert_eq!(entries[1].event, Event::Received(vec![0x79]));
+
+    let mut replay = Replay::new(entries);
+    write_memory(&mut replay, FLASH, &data, WriteOptions::default()).unwrap();
+    verify_memory(&mut replay, FLASH, &data, WriteOptions::default()).unwrap();
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn replay_reproduces_failure() {
+    let mut device = device();
+    device.inject(Fault::Timeout(Stage::Address));
+    let (res, entries) = record(device, |port| get_id(port).and_then(|_| go(port, FLASH)));
+    assert!(matches!(
+        res,
+        Err(BootloaderError::Timeout {
+            command: Command::Go,
+            stage: Stage::Address,
+            ..
+        })
+    ));
+    assert_eq!(entries.last().unwrap().event, Event::Timeout);
+
+    let mut replay = Replay::new(entries);
+    assert_eq!(get_id(&mut replay).unwrap(), 0x413);
+    assert!(matches!(
+        go(&mut replay, FLASH),
+        Err(BootloaderError::Timeout {
+            command: Command::Go,
+            stage: Stage::Address,
+            ..
+        })
+    ));
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn replay_detects_different_requests() {
+    let (res, entries) = record(device(), |port| go(port, FLASH));
+    res.unwrap();
+
+    let mut replay = Replay::new(entries);
+    match go(&mut replay, FLASH + 4) {
+        Err(BootloaderError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn verify_image_queries_commands_once() {
+    let mut image = MemoryImage::from_binary(FLASH, vec![0x11; 16]);
+    image.insert(FLASH + 0x4000, &[0x22; 16]).unwrap();
+    image.insert(FLASH + 0x8000, &[0x33; 16]).unwrap();
+    let (res, entries) = record(device(), |port| {
+        write_image(port, &image, WriteOptions::default())?;
+        verify_image(port, &image, WriteOptions::default())
+    });
+    res.unwrap();
+    let gets = entries
+        .iter()
+        .filter(|entry| entry.event == Event::Sent(vec![0x00, 0xFF]))
+        .count();
+    assert_eq!(gets, 1);
+}
+
+#[test]
+fn handshake_is_recorded() {
+    let mut device = MockDevice::new(MockConfig::default());
+    device.inject(Fault::Timeout(Stage::Command));
+    let (res, entries) = record(device, helper::synchronise);
+    res.unwrap();
+    let events = entries
+        .into_iter()
+        .map(|entry| entry.event)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            Event::Sent(vec![0x7F]),
+            Event::Timeout,
+            Event::Sent(vec![0x7F]),
+            Event::Received(vec![0x79]),
+        ]
+    );
+}